@@ -12,6 +12,16 @@ pub enum LendingError {
     InvalidAmount,
     #[msg("This is not the Owner of the Lending Market")]
     OwnerMismatch,
+    #[msg("The Lending Market config is invalid")]
+    InvalidConfig,
+    #[msg("The account uses an older layout and must be migrated")]
+    AccountNotMigrated,
+    #[msg("The account is already at the current version")]
+    AccountAlreadyMigrated,
+    #[msg("The account version can't be migrated")]
+    UnsupportedAccountVersion,
+    #[msg("The account is not a Lending Market, Reserve or Obligation")]
+    InvalidAccountType,
 
 
     #[msg("The account you passed is not a Reserve Account")]
@@ -28,6 +38,16 @@ pub enum LendingError {
     /// Action Errors
     #[msg("The position is not liquidatable")]
     NotLiquidatable,
+    #[msg("The position is liquidatable and can't be rolled over")]
+    PositionLiquidatable,
+    #[msg("The transaction executed after its valid until slot")]
+    TransactionExpired,
+    #[msg("The tokens received are below the minimum tokens out")]
+    TokensOutBelowMinimum,
+    #[msg("The SOL received is below the minimum SOL out")]
+    SolOutBelowMinimum,
+    #[msg("The fee charged is above the maximum fee")]
+    FeeAboveMaximum,
     #[msg("The Lending Market is not the same as the one in the Reserve Account")]
     LendingMarketMismatch,
     #[msg("The Loan type passed is invalid")]
@@ -38,6 +58,24 @@ pub enum LendingError {
     /// Reserve Errors
     #[msg("Insufficient liquidity in the Reserve Account to perform this action")] 
     InsufficientLiquidity,
+    #[msg("The Reserve is in reduced-risk mode and doesn't accept new borrows")]
+    ReserveReducedRisk,
+    #[msg("The account passed is not a transient stake account of this Reserve")]
+    InvalidTransientStake,
+    #[msg("The transient stake account is still in use by the Reserve")]
+    TransientStakeInUse,
+    #[msg("The mint passed doesn't belong to this Reserve")]
+    InvalidMint,
+
+    /// Validator Errors
+    #[msg("The account passed is not a valid Vote Account")]
+    InvalidVoteAccount,
+    #[msg("The validator commission is above the Lending Market limit")]
+    ValidatorCommissionTooHigh,
+    #[msg("The validator didn't earn enough credits in the last epoch")]
+    ValidatorInsufficientCredits,
+    #[msg("The validator is delinquent")]
+    ValidatorDelinquent,
 
     /// Obligation Errors
     #[msg("There are no positions in this Obligation Account")]
@@ -50,6 +88,26 @@ pub enum LendingError {
     LoanToValueTooHigh,
     #[msg("The Loan type passed for the loan creation is different from what is in the Obligation")]
     LoanTypeMismatch,
+    #[msg("The Obligation still has open positions")]
+    ObligationNotEmpty,
+    #[msg("The Obligation already holds the maximum number of positions")]
+    TooManyPositions,
+    #[msg("The position is tokenized and can only be repaid by the holder of its token")]
+    PositionTokenized,
+    #[msg("The token passed doesn't represent this position")]
+    InvalidPositionToken,
+    #[msg("The collateral token account isn't the one held by the Obligation")]
+    InvalidCollateralToken,
+    #[msg("The signer is neither the owner nor the delegate of the Obligation")]
+    ObligationAuthorityMismatch,
+    #[msg("The Obligation delegate is not allowed to perform this action")]
+    DelegateNotAllowed,
+    #[msg("The Obligation borrows exceed its borrowing capacity")]
+    ObligationUnhealthy,
+    #[msg("The reserves passed don't match the Obligation positions")]
+    InvalidObligationReserves,
+    #[msg("The position has collateral pending until the next epoch refresh")]
+    PendingCollateral,
 
     /// General Errors
     #[msg("Math overflow")]
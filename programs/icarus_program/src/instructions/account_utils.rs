@@ -0,0 +1,39 @@
+use {
+    crate::error::LendingError,
+    anchor_lang::prelude::*,
+    solana_program::{program::invoke, system_instruction},
+};
+
+/// Resize a program account, the payer funds the rent when it grows and the
+/// receiver gets the excess rent back when it shrinks
+pub fn resize_account<'info>(
+    account_info: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    receiver: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_space: usize,
+) -> Result<()> {
+    if account_info.data_len() == new_space {
+        return Ok(());
+    }
+
+    let minimum_balance = Rent::get()?.minimum_balance(new_space);
+    let current_balance = account_info.lamports();
+
+    if minimum_balance > current_balance {
+        invoke(
+            &system_instruction::transfer(payer.key, account_info.key, minimum_balance - current_balance),
+            &[payer.clone(), account_info.clone(), system_program.clone()],
+        )?;
+    } else {
+        let excess = current_balance - minimum_balance;
+        **account_info.try_borrow_mut_lamports()? = minimum_balance;
+        **receiver.try_borrow_mut_lamports()? = receiver.lamports()
+            .checked_add(excess)
+            .ok_or(LendingError::MathOverflow)?;
+    }
+
+    account_info.realloc(new_space, false)?;
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
 pub use {
     anchor_lang::prelude::*,
     solana_program::{system_instruction, program::invoke, stake},
-    crate::{get_stake_amount, state::{LendingMarket, Reserve, Obligation, LoanType, InitObligationPositionParams}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, OBLIGATION_PREFIX, RESERVE_STAKE_PREFIX},
+    crate::{resize_account, get_position_collaterals, check_valid_until_slot, check_min_out, get_effective_stake_amount, get_stake_amount, state::{Versioned, LendingMarket, Reserve, Obligation, ObligationAction, LoanType, InitObligationPositionParams}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, OBLIGATION_PREFIX, RESERVE_STAKE_PREFIX},
+    anchor_spl::{associated_token::AssociatedToken, token_interface::{TokenInterface, TokenAccount, mint_to, MintTo}},
 };
 
 #[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
@@ -9,18 +10,24 @@ pub struct BorrowLiquidityArgs {
     loan_type: u8,
     collateral_amount: u64,
     borrowed_amount: u64,
+    /// Minimum collateral tokens to receive
+    min_tokens_out: Option<u64>,
+    /// Last slot the borrow can execute in
+    valid_until_slot: Option<u64>,
 }
 
 #[derive(Accounts)]
 pub struct BorrowLiquidity<'info> {
     #[account(mut)]
     pub user: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
         mut,
         has_one = lending_market,
         seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
         bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub reserve: Account<'info, Reserve>,
     #[account(
@@ -33,10 +40,14 @@ pub struct BorrowLiquidity<'info> {
     #[account(
         mut,
         has_one = lending_market,
-        seeds = [OBLIGATION_PREFIX, obligation.owner.as_ref()],
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
         bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub obligation: Account<'info, Obligation>,
+    #[account(mut, address = obligation.owner @ LendingError::ObligationAuthorityMismatch)]
+    /// Funds the rent of a new position, refunded to it on repay, so it must sign when a position is created
+    pub obligation_owner: SystemAccount<'info>,
     #[account(
         seeds = [LENDING_MARKET_AUTHORITY_PREFIX, lending_market.key().as_ref()],
         bump = lending_market.authority_bump,
@@ -45,15 +56,33 @@ pub struct BorrowLiquidity<'info> {
     pub lending_market_authority: UncheckedAccount<'info>,
     /// CHECK: Validated in business logic
     pub stake_account: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        address = reserve.collateral.mint_pubkey,
+        owner = token_program.key(),
+    )]
+    /// CHECK: checked by the address constraint, migrated reserves keep their keypair mints
+    pub collateral_mint: UncheckedAccount<'info>,
+    /// Holds the Collateral Tokens of the position, created with it at the owner's expense
+    #[account(
+        init_if_needed,
+        payer = obligation_owner,
+        associated_token::mint = collateral_mint,
+        associated_token::authority = obligation,
+        associated_token::token_program = token_program,
+    )]
+    pub obligation_collateral_token: InterfaceAccount<'info, TokenAccount>,
     pub clock: Sysvar<'info, Clock>,
     pub stake_history: Sysvar<'info, StakeHistory>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
 impl<'info> BorrowLiquidity<'info> {
     fn merge_stake_account(&self, deposited_amount: u64) -> Result<()> {
         require_eq!(
-            get_stake_amount(&self.stake_account.to_account_info())?,
+            get_effective_stake_amount(&self.stake_account.to_account_info(), self.clock.epoch, &self.stake_history)?,
             deposited_amount,
             LendingError::InvalidStakeAmount
         );
@@ -123,46 +152,112 @@ impl<'info> BorrowLiquidity<'info> {
 
         Ok(())
     }
+
+    fn mint_collateral_tokens(&self, amount: u64) -> Result<()> {
+        let lending_market_key = self.lending_market.key();
+        let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
+        let signers = &[&authority_seeds[..]];
+
+        mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                MintTo {
+                    mint: self.collateral_mint.to_account_info(),
+                    to: self.obligation_collateral_token.to_account_info(),
+                    authority: self.lending_market_authority.to_account_info(),
+                },
+                signers
+            ),
+            amount
+        )?;
+
+        Ok(())
+    }
 }
 
 pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, BorrowLiquidity<'info>>, args: BorrowLiquidityArgs) -> Result<()> {
     // Validate loan type and get current epoch
     let current_epoch = Clock::get()?.epoch;
-    let loan_type = match args.loan_type {
-        0 => LoanType::SHORT(current_epoch),
-        1 => LoanType::MEDIUM(current_epoch),
-        2 => LoanType::LONG(current_epoch),
-        _ => return Err(LendingError::InvalidLoanType.into()),
-    };
+    let loan_type = LoanType::new(args.loan_type, current_epoch)?;
+    check_valid_until_slot(args.valid_until_slot)?;
+
+    // Only the owner or an allowed delegate can manage the obligation
+    let action = if args.borrowed_amount > 0 { ObligationAction::Borrow } else { ObligationAction::Deposit };
+    ctx.accounts.obligation.check_authority(ctx.accounts.user.key(), action)?;
+
+    // New borrows are paused while the validator is unhealthy
+    ctx.accounts.reserve.check_borrows_allowed()?;
+    let max_ratio = ctx.accounts.reserve.max_ratio(&loan_type, &ctx.accounts.lending_market.config)?;
 
     let deposited_amount = args.collateral_amount;
 
-    // Deposit collateral and update obligation
+    // Deposit collateral and update obligation, the collateral is pending until the next
+    // epoch refresh, a new position borrows against it with borrow_position_liquidity after
     let weighted_deposited_amount = ctx.accounts.reserve.deposit_collateral(deposited_amount)?;
+    check_min_out(weighted_deposited_amount, args.min_tokens_out, LendingError::TokensOutBelowMinimum)?;
     ctx.accounts.obligation.add_or_create_position(InitObligationPositionParams {
         vote_account: ctx.accounts.reserve.vote_account,
         loan_type,
         deposited_amount,
         weighted_deposited_amount,
         borrowed_amount: args.borrowed_amount,
-    })?;
+        max_ratio,
+        refreshed_epoch: ctx.accounts.reserve.last_epoch,
+    }, ctx.accounts.lending_market.config.max_obligation_positions)?;
+
+    // In cross collateral mode the reserves of the other positions come first in the remaining
+    // accounts, the borrow is checked against the collateral of all positions
+    let mut remaining_accounts = ctx.remaining_accounts;
+    if ctx.accounts.obligation.cross_collateral {
+        let reserve_count = ctx.accounts.obligation.positions.len().saturating_sub(1);
+        require_gte!(remaining_accounts.len(), reserve_count, LendingError::WrongRemainingAccountSchema);
+        let (reserve_accounts, stake_accounts) = remaining_accounts.split_at(reserve_count);
+        remaining_accounts = stake_accounts;
+
+        let collaterals = get_position_collaterals(
+            &ctx.accounts.obligation,
+            &ctx.accounts.lending_market.config,
+            Some(&ctx.accounts.reserve),
+            reserve_accounts,
+            Clock::get()?.slot,
+        )?;
+        ctx.accounts.obligation.check_health(&collaterals)?;
+    }
+
+    // Grow the obligation if a position was created, the owner funds the rent it gets back on repay
+    let obligation_space = Obligation::space(ctx.accounts.obligation.positions.len());
+    resize_account(
+        &ctx.accounts.obligation.to_account_info(),
+        &ctx.accounts.obligation_owner.to_account_info(),
+        &ctx.accounts.obligation_owner.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+        obligation_space,
+    )?;
 
     // Validate stake amount
-    let stake_amount = get_stake_amount(&ctx.accounts.stake_account.to_account_info())?;
+    let stake_amount = get_effective_stake_amount(&ctx.accounts.stake_account.to_account_info(), current_epoch, &ctx.accounts.stake_history)?;
     require_gte!(stake_amount, deposited_amount, LendingError::InsufficientCollateral);
 
     // Handle stake account operations
     if stake_amount == deposited_amount {
         ctx.accounts.merge_stake_account(deposited_amount)?;
     } else {
-        require_eq!(ctx.remaining_accounts.len(), 1, LendingError::WrongRemainingAccountSchema);
-        let split_stake_account = &ctx.remaining_accounts[0];
+        require_eq!(remaining_accounts.len(), 1, LendingError::WrongRemainingAccountSchema);
+        let split_stake_account = &remaining_accounts[0];
         let split_amount = stake_amount.checked_sub(deposited_amount).ok_or(LendingError::MathOverflow)?;
 
         ctx.accounts.split_stake_account(split_stake_account, split_amount)?;
         ctx.accounts.merge_stake_account(deposited_amount)?;
     }
 
+    // Mint Collateral Tokens for the weighted collateral to the obligation, they are burned when the position is settled
+    ctx.accounts.mint_collateral_tokens(weighted_deposited_amount)?;
+
+    // Snapshot the reserve stake so the merged stake isn't measured as rewards
+    let stake_lamports = ctx.accounts.reserve_stake.lamports();
+    let delegated_stake = get_stake_amount(&ctx.accounts.reserve_stake.to_account_info())?;
+    ctx.accounts.reserve.rewards.snapshot(stake_lamports, delegated_stake);
+
     // Mark Reserve as stale
     ctx.accounts.reserve.last_update.mark_stale();
 
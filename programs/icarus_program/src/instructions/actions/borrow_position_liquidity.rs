@@ -0,0 +1,107 @@
+pub use {
+    anchor_lang::prelude::*,
+    solana_program::{system_instruction, program::invoke_signed},
+    crate::{get_position_collaterals, check_valid_until_slot, state::{Versioned, LendingMarket, Reserve, Obligation, ObligationAction}, error::LendingError, RESERVE_PREFIX, OBLIGATION_PREFIX, LIQUIDITY_VAULT_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct BorrowPositionLiquidityArgs {
+    borrowed_amount: u64,
+    /// Last slot the borrow can execute in
+    valid_until_slot: Option<u64>,
+}
+
+#[derive(Accounts)]
+pub struct BorrowPositionLiquidity<'info> {
+    /// Owner or allowed delegate, receives the borrowed SOL
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
+        bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub reserve: Account<'info, Reserve>,
+    #[account(mut, address = reserve.liquidity.vault_pubkey)]
+    pub liquidity_vault: SystemAccount<'info>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> BorrowPositionLiquidity<'info> {
+    fn transfer_borrowed_liquidity(&self, amount: u64) -> Result<()> {
+        let reserve_key = self.reserve.key();
+        let vault_seeds = &[LIQUIDITY_VAULT_PREFIX, reserve_key.as_ref(), &[self.reserve.vault_bump]];
+
+        invoke_signed(
+            &system_instruction::transfer(self.liquidity_vault.key, self.user.key, amount),
+            &[
+                self.liquidity_vault.to_account_info(),
+                self.user.to_account_info(),
+                self.system_program.to_account_info(),
+            ],
+            &[&vault_seeds[..]],
+        )?;
+
+        Ok(())
+    }
+}
+
+pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, BorrowPositionLiquidity<'info>>, args: BorrowPositionLiquidityArgs) -> Result<()> {
+    require_gt!(args.borrowed_amount, 0, LendingError::InvalidAmount);
+    require!(!ctx.accounts.reserve.last_update.is_stale(Clock::get()?.slot)?, LendingError::ReserveStale);
+    check_valid_until_slot(args.valid_until_slot)?;
+
+    // Only the owner or an allowed delegate can borrow
+    ctx.accounts.obligation.check_authority(ctx.accounts.user.key(), ObligationAction::Borrow)?;
+
+    // New borrows are paused while the validator is unhealthy
+    ctx.accounts.reserve.check_borrows_allowed()?;
+
+    // Pending collateral counts once the reserve is refreshed in a later epoch
+    let vote_account = ctx.accounts.reserve.vote_account;
+    ctx.accounts.obligation.find_position_mut(vote_account)?.settle_pending(ctx.accounts.reserve.last_epoch)?;
+    let position = ctx.accounts.obligation.find_position(vote_account)?.0.clone();
+    require!(position.position_mint.is_none(), LendingError::PositionTokenized);
+
+    // Borrow against the current value of the collateral counted, no stake is moved
+    let config = &ctx.accounts.lending_market.config;
+    let reserve_max_ratio = ctx.accounts.reserve.max_ratio(&position.loan_type, config)?;
+    let max_ratio = ctx.accounts.obligation.position_max_ratio(reserve_max_ratio);
+    let collateral_value = ctx.accounts.reserve.collateral_value(position.weighted_deposited_amount)?;
+    ctx.accounts.obligation
+        .find_position_mut(vote_account)?
+        .borrow_against_value(args.borrowed_amount, collateral_value, max_ratio)?;
+
+    // In cross collateral mode the remaining accounts are the reserves of the other positions
+    if ctx.accounts.obligation.cross_collateral {
+        let collaterals = get_position_collaterals(
+            &ctx.accounts.obligation,
+            config,
+            Some(&ctx.accounts.reserve),
+            ctx.remaining_accounts,
+            Clock::get()?.slot,
+        )?;
+        ctx.accounts.obligation.check_health(&collaterals)?;
+    }
+
+    // Lend the SOL out of the reserve vault to the borrower
+    ctx.accounts.reserve.liquidity.borrow(args.borrowed_amount)?;
+    ctx.accounts.transfer_borrowed_liquidity(args.borrowed_amount)?;
+
+    // Mark Reserve as stale
+    ctx.accounts.reserve.last_update.mark_stale();
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{state::{Versioned, LendingMarket, Obligation}, error::LendingError, OBLIGATION_PREFIX},
+};
+
+#[derive(Accounts)]
+pub struct CloseObligation<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        close = owner,
+        has_one = lending_market,
+        has_one = owner @ LendingError::ObligationAuthorityMismatch,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+}
+
+pub fn handler(ctx: Context<CloseObligation>) -> Result<()> {
+    // Only an obligation without open positions can be closed
+    require!(ctx.accounts.obligation.positions.is_empty(), LendingError::ObligationNotEmpty);
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 pub use {
     anchor_lang::prelude::*,
-    crate::{state::{LendingMarket, Obligation, InitObligationParams}, LENDING_MARKET_PREFIX, OBLIGATION_PREFIX},
+    crate::{error::LendingError, state::{Versioned, LendingMarket, Obligation, InitObligationParams}, LENDING_MARKET_PREFIX, OBLIGATION_PREFIX},
 };
 
 #[derive(Accounts)]
@@ -10,6 +10,7 @@ pub struct InitializeObligation<'info> {
     #[account(
         seeds = [LENDING_MARKET_PREFIX],
         bump = lending_market.bump,
+        constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
@@ -7,9 +7,23 @@ pub use reedem_reserve_liquidity::*;
 
 /// Collateral Managment
 pub mod init_obligation;
+pub mod transfer_obligation;
+pub mod set_obligation_delegate;
+pub mod set_obligation_cross_collateral;
+pub mod close_obligation;
 pub mod borrow_obligation_liquidity;
+pub mod borrow_position_liquidity;
 pub mod repay_obligation_liquidity;
+pub mod rollover_position;
+pub mod tokenize_position;
 
 pub use init_obligation::*;
+pub use transfer_obligation::*;
+pub use set_obligation_delegate::*;
+pub use set_obligation_cross_collateral::*;
+pub use close_obligation::*;
 pub use borrow_obligation_liquidity::*;
+pub use borrow_position_liquidity::*;
 pub use repay_obligation_liquidity::*;
+pub use rollover_position::*;
+pub use tokenize_position::*;
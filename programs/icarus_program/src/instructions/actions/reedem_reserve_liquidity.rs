@@ -1,25 +1,31 @@
 pub use {
     anchor_lang::prelude::*,
     solana_program::{system_instruction, program::invoke_signed},
-    crate::{state::{LendingMarket, Reserve}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, LIQUIDITY_VAULT_PREFIX },
-    anchor_spl::token::{Token, TokenAccount, burn, Burn},
+    crate::{check_valid_until_slot, check_min_out, state::{Versioned, LendingMarket, Reserve}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, LIQUIDITY_VAULT_PREFIX },
+    anchor_spl::token_interface::{TokenInterface, TokenAccount, burn, Burn},
 };
 
 #[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
 pub struct RedeemLiquidityArgs {
     token_amount: u64,
+    /// Minimum SOL to receive
+    min_sol_out: Option<u64>,
+    /// Last slot the redeem can execute in
+    valid_until_slot: Option<u64>,
 }
 
 #[derive(Accounts)]
 pub struct RedeemLiquidity<'info> {
     #[account(mut)]
     pub user: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
         mut,
         has_one = lending_market,
         seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
         bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub reserve: Account<'info, Reserve>,
     #[account(
@@ -27,7 +33,11 @@ pub struct RedeemLiquidity<'info> {
         bump = lending_market.authority_bump,
     )]
     pub lending_market_authority: UncheckedAccount<'info>,
-    #[account(mut, address = reserve.liquidity.mint_pubkey)]
+    #[account(
+        mut,
+        address = reserve.liquidity.mint_pubkey,
+        owner = token_program.key(),
+    )]
     pub liquidity_mint: UncheckedAccount<'info>,
     #[account(mut, address = reserve.liquidity.vault_pubkey)]
     pub liquidity_vault: SystemAccount<'info>,
@@ -35,9 +45,10 @@ pub struct RedeemLiquidity<'info> {
         mut,
         token::mint = liquidity_mint,
         token::authority = user,
+        token::token_program = token_program,
     )]
-    pub user_liquidity_token: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub user_liquidity_token: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>
 }
 
@@ -77,9 +88,11 @@ pub fn handler<'info>(ctx: Context<RedeemLiquidity>, args: RedeemLiquidityArgs)
     // CHECKS: todo
     require!(args.token_amount > 0, LendingError::InvalidAmount);
     require!(!ctx.accounts.reserve.last_update.is_stale(Clock::get()?.slot)?, LendingError::ReserveStale);
+    check_valid_until_slot(args.valid_until_slot)?;
 
     // Deposit
     let liquidity_amount = ctx.accounts.reserve.reedem(args.token_amount)?;
+    check_min_out(liquidity_amount, args.min_sol_out, LendingError::SolOutBelowMinimum)?;
     ctx.accounts.reedem_liquidity(liquidity_amount)?;
 
     // Mark Reserve as Stale to force refresh
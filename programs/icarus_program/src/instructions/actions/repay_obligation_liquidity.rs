@@ -1,20 +1,33 @@
 pub use {
     anchor_lang::prelude::*,
     solana_program::{system_instruction, program::{invoke, invoke_signed}, stake::{self, program::ID as STAKE_PROGRAM_ID}},
-    crate::{get_stake_amount, calculate_withdraw_amount, state::{LendingMarket, Reserve, Obligation}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, RESERVE_STAKE_PREFIX, OBLIGATION_PREFIX},
-    anchor_spl::token::{Token, TokenAccount, mint_to, MintTo},
+    crate::{resize_account, get_position_collaterals, ObligationCollateralToken, check_valid_until_slot, check_min_out, get_stake_amount, state::{Versioned, LendingMarket, Reserve, Obligation, SettlementQuoteParams}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, RESERVE_STAKE_PREFIX, OBLIGATION_PREFIX},
+    anchor_spl::token_interface::{Mint, TokenInterface, TokenAccount, burn, Burn},
 };
 
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct RepayLiquidityArgs {
+    /// Minimum stake to receive back
+    min_sol_out: Option<u64>,
+    /// Maximum fee to pay on settlement
+    max_fee: Option<u64>,
+    /// Last slot the repay can execute in
+    valid_until_slot: Option<u64>,
+}
+
 #[derive(Accounts)]
 pub struct RepayLiquidity<'info> {
+    /// Owner, allowed delegate or position token holder, burns the position token of a tokenized position
     #[account(mut)]
     pub user: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
         mut,
         has_one = lending_market,
         seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
         bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub reserve: Account<'info, Reserve>,
     #[account(
@@ -32,20 +45,78 @@ pub struct RepayLiquidity<'info> {
     #[account(
         mut,
         has_one = lending_market,
-        seeds = [OBLIGATION_PREFIX, user.key().as_ref()],
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
         bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub obligation: Account<'info, Obligation>,
+    #[account(mut, address = obligation.owner @ LendingError::ObligationAuthorityMismatch)]
+    /// Receives the rent freed by the removed position and its collateral token account
+    pub obligation_owner: SystemAccount<'info>,
+    #[account(
+        mut,
+        address = reserve.collateral.mint_pubkey,
+        owner = token_program.key(),
+    )]
+    /// CHECK: checked by the address constraint, migrated reserves keep their keypair mints
+    pub collateral_mint: UncheckedAccount<'info>,
+    #[account(mut)]
+    /// CHECK: checked against the associated token account of the obligation
+    pub obligation_collateral_token: UncheckedAccount<'info>,
+    /// Only needed when the position is tokenized
+    #[account(mut)]
+    pub position_mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(
+        mut,
+        token::authority = user,
+        token::token_program = token_program,
+    )]
+    pub user_position_token: Option<InterfaceAccount<'info, TokenAccount>>,
     pub clock: Sysvar<'info, Clock>,
+    pub stake_history: Sysvar<'info, StakeHistory>,
     #[account(address = STAKE_PROGRAM_ID)]
     /// CHECK: checked by address constraint
     pub stake_program: UncheckedAccount<'info>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
 impl<'info> RepayLiquidity<'info> {
-    fn split_stake_account(&self, new_stake_account: &AccountInfo<'info>, split_amount: u64) -> Result<()> {
+    fn close_collateral_token(&self) -> Result<()> {
+        let creator = self.obligation.creator;
+        let obligation_seeds = &[OBLIGATION_PREFIX, creator.as_ref(), &[self.obligation.bump]];
+
+        ObligationCollateralToken {
+            token_account: &self.obligation_collateral_token.to_account_info(),
+            obligation: &self.obligation.to_account_info(),
+            obligation_seeds,
+            collateral_mint: &self.collateral_mint.to_account_info(),
+            token_program: &self.token_program.to_account_info(),
+        }.close(&self.obligation_owner.to_account_info())
+    }
+
+    fn burn_position_token(&self, position_mint: Pubkey) -> Result<()> {
+        let mint = self.position_mint.as_ref().ok_or(LendingError::InvalidPositionToken)?;
+        let user_position_token = self.user_position_token.as_ref().ok_or(LendingError::InvalidPositionToken)?;
+        require_keys_eq!(mint.key(), position_mint, LendingError::InvalidPositionToken);
+        require_keys_eq!(user_position_token.mint, position_mint, LendingError::InvalidPositionToken);
+
+        burn(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                Burn {
+                    mint: mint.to_account_info(),
+                    from: user_position_token.to_account_info(),
+                    authority: self.user.to_account_info(),
+                }
+            ),
+            1
+        )?;
+
+        Ok(())
+    }
+
+    fn split_stake_account(&self, new_stake_account: &AccountInfo<'info>, split_amount: u64, recipient: &Pubkey) -> Result<()> {
         let lending_market_key = self.reserve.lending_market;
         let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
         let signers = &[&authority_seeds[..]];
@@ -71,7 +142,7 @@ impl<'info> RepayLiquidity<'info> {
             &stake::instruction::authorize(
                 new_stake_account.key,
                 &self.lending_market_authority.key,
-                &self.user.key,  
+                recipient,
                 stake::state::StakeAuthorize::Staker,
                 None,
             ),
@@ -87,7 +158,7 @@ impl<'info> RepayLiquidity<'info> {
             &stake::instruction::authorize(
                 new_stake_account.key,
                 &self.lending_market_authority.key,
-                &self.user.key,  
+                recipient,
                 stake::state::StakeAuthorize::Withdrawer,
                 None,
             ),
@@ -103,75 +174,99 @@ impl<'info> RepayLiquidity<'info> {
     }
 }
 
-pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, RepayLiquidity<'info>>) -> Result<()> {
+pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, RepayLiquidity<'info>>, args: RepayLiquidityArgs) -> Result<()> {
     require!(!ctx.accounts.reserve.last_update.is_stale(Clock::get()?.slot)?, LendingError::ReserveStale);
+    check_valid_until_slot(args.valid_until_slot)?;
 
     let vote_account = ctx.accounts.reserve.vote_account;
     let current_epoch = Clock::get()?.epoch;
 
-    // Repay or liquidate
-    let (is_liquidatable, deposited_amount_after_fee) = ctx.accounts.obligation.repay_or_liquidate(vote_account, current_epoch)?;
-    let position = ctx.accounts.obligation.find_position(vote_account)?.0;
+    // Only the owner, an allowed delegate or the holder of a tokenized position can settle it
+    let recipient = ctx.accounts.obligation.settlement_recipient(ctx.accounts.user.key(), vote_account)?;
+
+    // Pending collateral counts once the reserve is refreshed in a later epoch
+    let refreshed_epoch = ctx.accounts.reserve.last_epoch;
+    ctx.accounts.obligation.find_position_mut(vote_account)?.settle_pending(refreshed_epoch)?;
+
+    // Repay or liquidate, the position is removed from the obligation so keep a copy
+    let position = ctx.accounts.obligation.find_position(vote_account)?.0.clone();
+    let total_weighted_deposited_amount = position.total_weighted_deposited_amount()?;
+    let (is_liquidatable, deposited_amount_after_fee) = ctx.accounts.obligation.repay_or_liquidate(vote_account, current_epoch, &ctx.accounts.lending_market.config)?;
+
+    // Shrink the obligation and refund the rent of the removed position to the owner
+    let obligation_space = Obligation::space(ctx.accounts.obligation.positions.len());
+    resize_account(
+        &ctx.accounts.obligation.to_account_info(),
+        &ctx.accounts.obligation_owner.to_account_info(),
+        &ctx.accounts.obligation_owner.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+        obligation_space,
+    )?;
+
+    // Burn the Position Token of a tokenized position, and the Collateral Tokens held by the obligation
+    if let Some(position_mint) = position.position_mint {
+        ctx.accounts.burn_position_token(position_mint)?;
+    }
+    ctx.accounts.close_collateral_token()?;
+
+    // Value the collateral with the rewards measured on the reserve stake, pending collateral is returned without fees
+    let stake_current_value = ctx.accounts.reserve.collateral_value(position.weighted_deposited_amount)?;
+    let pending_value = ctx.accounts.reserve.collateral_value(position.pending_weighted_deposited_amount)?;
+    let total_value = stake_current_value.checked_add(pending_value).ok_or(LendingError::MathOverflow)?;
 
     if is_liquidatable {
-        ctx.accounts.reserve.collateral.repay_or_liquidate(position.deposited_amount, position.deposited_amount, position.weighted_deposited_amount)?;
+        // Nothing is returned to the holder of a liquidated position
+        check_min_out(0, args.min_sol_out, LendingError::SolOutBelowMinimum)?;
+        ctx.accounts.reserve.collateral.repay_or_liquidate(total_value, total_value, position.borrowed_amount, total_weighted_deposited_amount)?;
         return Ok(());
     }
 
-    // Calculate fees and amounts
-    let stake_amount = get_stake_amount(&ctx.accounts.reserve_stake.to_account_info())?;
-    let stake_current_value = calculate_withdraw_amount(
-        ctx.accounts.reserve.collateral.mint_total_supply,
-        stake_amount,
-        position.weighted_deposited_amount,
-    )?;
+    // Calculate fees and amounts, the reward share is waived if the validator rugged its commission during the loan
+    let loan_start_epoch = position.loan_type.epoch();
+    let quote = position.settlement_quote(SettlementQuoteParams {
+        stake_current_value,
+        deposited_amount_after_fee,
+        is_fee_waived: ctx.accounts.reserve.is_fee_waived(&ctx.accounts.lending_market.config, loan_start_epoch),
+        minimum_fee_bps: ctx.accounts.reserve.fees.minimum_fee_bps(loan_start_epoch, current_epoch),
+    })?;
 
-    let ltv_to_max_ratio = position.get_ltv_to_max_ratio()?;
+    if let Some(max_fee) = args.max_fee {
+        require_gte!(max_fee, quote.fee, LendingError::FeeAboveMaximum);
+    }
 
-    let mut fee_to_collect = u64::try_from((stake_current_value as u128)
-        .checked_sub(position.deposited_amount as u128)
-        .and_then(|v| v.checked_mul(ltv_to_max_ratio as u128))
-        .and_then(|v| v.checked_div(100))
-        .ok_or(LendingError::MathOverflow)?
-    )?;
+    // The fee is claimed by the reserve for the liquidity providers
+    ctx.accounts.reserve.collateral.repay_or_liquidate(total_value, quote.fee, 0, total_weighted_deposited_amount)?;
 
-    if position.deposited_amount != deposited_amount_after_fee {
-        fee_to_collect = fee_to_collect.checked_add(
-            position.deposited_amount
-                .checked_sub(deposited_amount_after_fee)
-                .ok_or(LendingError::MathOverflow)?
-        ).ok_or(LendingError::MathOverflow)?;
-    }
+    // The split stake account is followed by the reserves of the other positions in cross collateral mode
+    require!(!ctx.remaining_accounts.is_empty(), LendingError::WrongRemainingAccountSchema);
+    let (split_stake_account, reserve_accounts) = (&ctx.remaining_accounts[0], &ctx.remaining_accounts[1..]);
 
-    ctx.accounts.reserve.collateral.repay_or_liquidate(position.deposited_amount, fee_to_collect, position.weighted_deposited_amount)?;
+    // The other positions must still cover their borrows without the repaid collateral
+    if ctx.accounts.obligation.cross_collateral {
+        let collaterals = get_position_collaterals(
+            &ctx.accounts.obligation,
+            &ctx.accounts.lending_market.config,
+            None,
+            reserve_accounts,
+            Clock::get()?.slot,
+        )?;
+        ctx.accounts.obligation.check_health(&collaterals)?;
+    } else {
+        require!(reserve_accounts.is_empty(), LendingError::WrongRemainingAccountSchema);
+    }
 
     // Split stake account
-    require_eq!(ctx.remaining_accounts.len(), 1, LendingError::WrongRemainingAccountSchema);
-    let split_stake_account = &ctx.remaining_accounts[0];
-    let split_amount = stake_current_value.checked_sub(fee_to_collect).ok_or(LendingError::MathOverflow)?;
-    ctx.accounts.split_stake_account(split_stake_account, split_amount)?;
+    let split_amount = quote.returned_amount.checked_add(pending_value).ok_or(LendingError::MathOverflow)?;
+    check_min_out(split_amount, args.min_sol_out, LendingError::SolOutBelowMinimum)?;
+    ctx.accounts.split_stake_account(split_stake_account, split_amount, &recipient)?;
+
+    // Snapshot the reserve stake so the split stake isn't measured as a loss
+    let stake_lamports = ctx.accounts.reserve_stake.lamports();
+    let delegated_stake = get_stake_amount(&ctx.accounts.reserve_stake.to_account_info())?;
+    ctx.accounts.reserve.rewards.snapshot(stake_lamports, delegated_stake);
 
     // Mark Reserve as stale
     ctx.accounts.reserve.last_update.mark_stale();
 
     Ok(())
 }
-
-fn calculate_fee(stake_current_value: u64, deposited_amount: u64, deposited_amount_after_fee: u64, ltv_to_max_ratio: u64) -> Result<u64> {
-    let mut fee = u64::try_from((stake_current_value as u128)
-        .checked_sub(deposited_amount as u128)
-        .and_then(|v| v.checked_mul(ltv_to_max_ratio as u128))
-        .and_then(|v| v.checked_div(100))
-        .ok_or(LendingError::MathOverflow)?
-    )?;
-
-    if deposited_amount != deposited_amount_after_fee {
-        fee = fee.checked_add(
-            deposited_amount
-                .checked_sub(deposited_amount_after_fee)
-                .ok_or(LendingError::MathOverflow)?
-        ).ok_or(LendingError::MathOverflow)?;
-    }
-
-    Ok(fee)
-}
\ No newline at end of file
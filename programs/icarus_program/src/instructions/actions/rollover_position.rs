@@ -0,0 +1,132 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{get_position_collaterals, ObligationCollateralToken, state::{Versioned, LendingMarket, Reserve, Obligation, ObligationAction, LoanType, SettlementQuoteParams}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, OBLIGATION_PREFIX},
+    anchor_spl::token_interface::TokenInterface,
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct RolloverPositionArgs {
+    /// Loan type of the restarted position
+    loan_type: u8,
+}
+
+#[derive(Accounts)]
+pub struct RolloverPosition<'info> {
+    pub user: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
+        bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub reserve: Account<'info, Reserve>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+    #[account(
+        seeds = [LENDING_MARKET_AUTHORITY_PREFIX, lending_market.key().as_ref()],
+        bump = lending_market.authority_bump,
+    )]
+    /// CHECK: PDA account, no need to validate
+    pub lending_market_authority: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        address = reserve.collateral.mint_pubkey,
+        owner = token_program.key(),
+    )]
+    /// CHECK: checked by the address constraint, migrated reserves keep their keypair mints
+    pub collateral_mint: UncheckedAccount<'info>,
+    #[account(mut)]
+    /// CHECK: checked against the associated token account of the obligation
+    pub obligation_collateral_token: UncheckedAccount<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+impl<'info> RolloverPosition<'info> {
+    fn adjust_collateral_tokens(&self, previous_amount: u64, amount: u64) -> Result<()> {
+        let creator = self.obligation.creator;
+        let obligation_seeds = &[OBLIGATION_PREFIX, creator.as_ref(), &[self.obligation.bump]];
+        let collateral_token = ObligationCollateralToken {
+            token_account: &self.obligation_collateral_token.to_account_info(),
+            obligation: &self.obligation.to_account_info(),
+            obligation_seeds,
+            collateral_mint: &self.collateral_mint.to_account_info(),
+            token_program: &self.token_program.to_account_info(),
+        };
+
+        if amount > previous_amount {
+            let lending_market_key = self.lending_market.key();
+            let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
+            collateral_token.mint(&self.lending_market_authority.to_account_info(), authority_seeds, amount - previous_amount)
+        } else {
+            collateral_token.burn(previous_amount - amount)
+        }
+    }
+}
+
+pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, RolloverPosition<'info>>, args: RolloverPositionArgs) -> Result<()> {
+    require!(!ctx.accounts.reserve.last_update.is_stale(Clock::get()?.slot)?, LendingError::ReserveStale);
+
+    // Restarting a loan is a new borrow term
+    ctx.accounts.obligation.check_authority(ctx.accounts.user.key(), ObligationAction::Borrow)?;
+
+    let vote_account = ctx.accounts.reserve.vote_account;
+    let current_epoch = Clock::get()?.epoch;
+    let loan_type = LoanType::new(args.loan_type, current_epoch)?;
+    let reserve_max_ratio = ctx.accounts.reserve.max_ratio(&loan_type, &ctx.accounts.lending_market.config)?;
+    let max_ratio = ctx.accounts.obligation.position_max_ratio(reserve_max_ratio);
+
+    // Pending collateral counts once the reserve is refreshed in a later epoch, until then the position can't roll over
+    ctx.accounts.obligation.find_position_mut(vote_account)?.settle_pending(ctx.accounts.reserve.last_epoch)?;
+    let position = ctx.accounts.obligation.find_position(vote_account)?.0.clone();
+    require!(position.position_mint.is_none(), LendingError::PositionTokenized);
+
+    let (is_liquidatable, deposited_amount_after_fee) = position.get_deposit_status(current_epoch, &ctx.accounts.lending_market.config)?;
+    require!(!is_liquidatable, LendingError::PositionLiquidatable);
+
+    // Settle the fees of the elapsed term, they stay in the reserve stake to be claimed
+    let stake_current_value = ctx.accounts.reserve.collateral_value(position.weighted_deposited_amount)?;
+    let loan_start_epoch = position.loan_type.epoch();
+    let quote = position.settlement_quote(SettlementQuoteParams {
+        stake_current_value,
+        deposited_amount_after_fee,
+        is_fee_waived: ctx.accounts.reserve.is_fee_waived(&ctx.accounts.lending_market.config, loan_start_epoch),
+        minimum_fee_bps: ctx.accounts.reserve.fees.minimum_fee_bps(loan_start_epoch, current_epoch),
+    })?;
+    ctx.accounts.reserve.collateral.repay_or_liquidate(stake_current_value, quote.fee, 0, position.weighted_deposited_amount)?;
+
+    // Restart the position with the collateral left
+    let deposited_amount = quote.returned_amount;
+    let weighted_deposited_amount = ctx.accounts.reserve.deposit_collateral(deposited_amount)?;
+    ctx.accounts.obligation
+        .find_position_mut(vote_account)?
+        .rollover(loan_type, deposited_amount, weighted_deposited_amount, max_ratio)?;
+
+    // In cross collateral mode the remaining accounts are the reserves of the other positions
+    if ctx.accounts.obligation.cross_collateral {
+        let collaterals = get_position_collaterals(
+            &ctx.accounts.obligation,
+            &ctx.accounts.lending_market.config,
+            Some(&ctx.accounts.reserve),
+            ctx.remaining_accounts,
+            Clock::get()?.slot,
+        )?;
+        ctx.accounts.obligation.check_health(&collaterals)?;
+    }
+
+    // Keep the Collateral Tokens equal to the weighted collateral of the restarted position
+    ctx.accounts.adjust_collateral_tokens(position.weighted_deposited_amount, weighted_deposited_amount)?;
+
+    // Mark Reserve as stale
+    ctx.accounts.reserve.last_update.mark_stale();
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{get_position_collaterals, state::{Versioned, LendingMarket, Obligation}, error::LendingError, OBLIGATION_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct SetObligationCrossCollateralArgs {
+    /// Back borrows with the collateral of all positions
+    pub cross_collateral: bool,
+}
+
+#[derive(Accounts)]
+pub struct SetObligationCrossCollateral<'info> {
+    pub owner: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        has_one = owner @ LendingError::ObligationAuthorityMismatch,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+}
+
+pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, SetObligationCrossCollateral<'info>>, args: SetObligationCrossCollateralArgs) -> Result<()> {
+    // Value the positions with their reserves, passed as remaining accounts in position order
+    let collaterals = get_position_collaterals(
+        &ctx.accounts.obligation,
+        &ctx.accounts.lending_market.config,
+        None,
+        ctx.remaining_accounts,
+        Clock::get()?.slot,
+    )?;
+
+    // Switch mode, the positions must be healthy in the new one
+    ctx.accounts.obligation.set_cross_collateral(args.cross_collateral, &collaterals)?;
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{state::{Versioned, LendingMarket, Obligation, ObligationDelegate}, error::LendingError, OBLIGATION_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct SetObligationDelegateArgs {
+    /// New delegate, `None` revokes the current one
+    pub delegate: Option<ObligationDelegate>,
+}
+
+#[derive(Accounts)]
+pub struct SetObligationDelegate<'info> {
+    pub owner: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        has_one = owner @ LendingError::ObligationAuthorityMismatch,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+}
+
+pub fn handler(ctx: Context<SetObligationDelegate>, args: SetObligationDelegateArgs) -> Result<()> {
+    // Set Obligation Delegate
+    ctx.accounts.obligation.delegate = args.delegate;
+
+    Ok(())
+}
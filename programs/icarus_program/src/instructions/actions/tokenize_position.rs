@@ -0,0 +1,140 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{ObligationCollateralToken, state::{Versioned, LendingMarket, Reserve, Obligation, ObligationAction}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, OBLIGATION_PREFIX, POSITION_MINT_PREFIX},
+    anchor_spl::token_interface::{Mint, TokenInterface, TokenAccount, mint_to, MintTo, set_authority, SetAuthority, spl_token_2022::instruction::AuthorityType},
+};
+
+#[derive(Accounts)]
+pub struct TokenizePosition<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        has_one = lending_market,
+        seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
+        bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub reserve: Account<'info, Reserve>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+    #[account(
+        seeds = [LENDING_MARKET_AUTHORITY_PREFIX, lending_market.key().as_ref()],
+        bump = lending_market.authority_bump,
+    )]
+    /// CHECK: PDA account, no need to validate
+    pub lending_market_authority: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        address = reserve.collateral.mint_pubkey,
+        owner = token_program.key(),
+    )]
+    /// CHECK: checked by the address constraint, migrated reserves keep their keypair mints
+    pub collateral_mint: UncheckedAccount<'info>,
+    #[account(mut)]
+    /// CHECK: checked against the associated token account of the obligation
+    pub obligation_collateral_token: UncheckedAccount<'info>,
+    #[account(
+        init,
+        payer = user,
+        seeds = [
+            POSITION_MINT_PREFIX,
+            obligation.key().as_ref(),
+            reserve.vote_account.as_ref(),
+            obligation.find_loan_type(reserve.vote_account)?.epoch().to_le_bytes().as_ref(),
+        ],
+        bump,
+        mint::decimals = 0,
+        mint::authority = lending_market_authority,
+        mint::token_program = token_program,
+    )]
+    pub position_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = user,
+        token::mint = position_mint,
+        token::authority = user,
+        token::token_program = token_program,
+    )]
+    pub user_position_token: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> TokenizePosition<'info> {
+    fn burn_collateral_tokens(&self, amount: u64) -> Result<()> {
+        let creator = self.obligation.creator;
+        let obligation_seeds = &[OBLIGATION_PREFIX, creator.as_ref(), &[self.obligation.bump]];
+
+        ObligationCollateralToken {
+            token_account: &self.obligation_collateral_token.to_account_info(),
+            obligation: &self.obligation.to_account_info(),
+            obligation_seeds,
+            collateral_mint: &self.collateral_mint.to_account_info(),
+            token_program: &self.token_program.to_account_info(),
+        }.burn(amount)
+    }
+
+    fn mint_position_token(&self) -> Result<()> {
+        let lending_market_key = self.lending_market.key();
+        let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
+        let signers = &[&authority_seeds[..]];
+
+        mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                MintTo {
+                    mint: self.position_mint.to_account_info(),
+                    to: self.user_position_token.to_account_info(),
+                    authority: self.lending_market_authority.to_account_info(),
+                },
+                signers
+            ),
+            1
+        )?;
+
+        // Remove the mint authority to keep the supply at one
+        set_authority(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: self.lending_market_authority.to_account_info(),
+                    account_or_mint: self.position_mint.to_account_info(),
+                },
+                signers
+            ),
+            AuthorityType::MintTokens,
+            None,
+        )?;
+
+        Ok(())
+    }
+}
+
+pub fn handler(ctx: Context<TokenizePosition>) -> Result<()> {
+    // Tokenizing moves the collateral claim out of the obligation
+    ctx.accounts.obligation.check_authority(ctx.accounts.user.key(), ObligationAction::Withdraw)?;
+
+    let vote_account = ctx.accounts.reserve.vote_account;
+    let position_mint = ctx.accounts.position_mint.key();
+
+    // Record the Position Mint
+    let position = ctx.accounts.obligation.find_position_mut(vote_account)?;
+    position.tokenize(position_mint)?;
+    let weighted_deposited_amount = position.total_weighted_deposited_amount()?;
+
+    // The position token replaces the collateral tokens held by the obligation, it can be transferred
+    ctx.accounts.burn_collateral_tokens(weighted_deposited_amount)?;
+
+    // Mint Position Token
+    ctx.accounts.mint_position_token()?;
+
+    Ok(())
+}
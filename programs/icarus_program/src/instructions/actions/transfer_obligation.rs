@@ -0,0 +1,34 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{state::{Versioned, LendingMarket, Obligation}, error::LendingError, OBLIGATION_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct TransferObligationArgs {
+    pub new_owner: Pubkey,
+}
+
+/// The obligation keeps the address derived from its creator, so the creator can't open a
+/// new obligation until the new owner closes this one
+#[derive(Accounts)]
+pub struct TransferObligation<'info> {
+    pub owner: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        has_one = owner @ LendingError::ObligationAuthorityMismatch,
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
+        bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub obligation: Account<'info, Obligation>,
+}
+
+pub fn handler(ctx: Context<TransferObligation>, args: TransferObligationArgs) -> Result<()> {
+    // Transfer Obligation
+    ctx.accounts.obligation.transfer(args.new_owner);
+
+    Ok(())
+}
@@ -0,0 +1,26 @@
+use {
+    crate::error::LendingError,
+    anchor_lang::prelude::*,
+    solana_program::clock::Slot,
+};
+
+/// Check that the instruction executes no later than the slot the user allowed
+pub fn check_valid_until_slot(valid_until_slot: Option<Slot>) -> Result<()> {
+    if let Some(valid_until_slot) = valid_until_slot {
+        require_gte!(valid_until_slot, Clock::get()?.slot, LendingError::TransactionExpired);
+    }
+
+    Ok(())
+}
+
+/// Check an amount received against the minimum the user accepts
+pub fn check_min_out(amount: u64, min_amount: Option<u64>, error: LendingError) -> Result<()> {
+    if let Some(min_amount) = min_amount {
+        if amount < min_amount {
+            msg!("Received {} below the minimum of {}", amount, min_amount);
+            return Err(error.into());
+        }
+    }
+
+    Ok(())
+}
@@ -6,3 +6,16 @@ pub use actions::*;
 
 pub mod stake_utils;
 pub use stake_utils::*;
+
+pub mod vote_utils;
+pub use vote_utils::*;
+pub mod account_utils;
+pub use account_utils::*;
+pub mod obligation_utils;
+pub use obligation_utils::*;
+
+pub mod guard_utils;
+pub use guard_utils::*;
+
+pub mod token_utils;
+pub use token_utils::*;
@@ -0,0 +1,46 @@
+use {
+    crate::{error::LendingError, state::{Versioned, Obligation, Reserve, LendingMarketConfig, PositionCollateral}},
+    anchor_lang::prelude::*,
+    solana_program::clock::Slot,
+};
+
+/// Value the collateral of every obligation position with its reserve. The reserve loaded by
+/// the instruction is used for its own position, the reserves of the other positions are
+/// passed in position order
+pub fn get_position_collaterals(
+    obligation: &Obligation,
+    config: &LendingMarketConfig,
+    current_reserve: Option<&Reserve>,
+    reserve_accounts: &[AccountInfo],
+    current_slot: Slot,
+) -> Result<Vec<PositionCollateral>> {
+    let mut reserve_accounts = reserve_accounts.iter();
+    let mut collaterals = Vec::with_capacity(obligation.positions.len());
+
+    for position in &obligation.positions {
+        let loaded_reserve;
+        let reserve = match current_reserve {
+            Some(reserve) if reserve.vote_account == position.vote_account => reserve,
+            _ => {
+                let reserve_account = reserve_accounts.next().ok_or(LendingError::WrongRemainingAccountSchema)?;
+                require_keys_eq!(*reserve_account.owner, crate::ID, LendingError::InvalidObligationReserves);
+                loaded_reserve = Reserve::try_deserialize(&mut &reserve_account.data.borrow()[..])?;
+                require!(loaded_reserve.is_current_version(), LendingError::AccountNotMigrated);
+                require_keys_eq!(loaded_reserve.lending_market, obligation.lending_market, LendingError::InvalidObligationReserves);
+                require_keys_eq!(loaded_reserve.vote_account, position.vote_account, LendingError::InvalidObligationReserves);
+                require!(!loaded_reserve.last_update.is_stale(current_slot)?, LendingError::ReserveStale);
+                &loaded_reserve
+            },
+        };
+
+        collaterals.push(PositionCollateral {
+            vote_account: position.vote_account,
+            collateral_value: reserve.collateral_value(position.weighted_deposited_amount)?,
+            max_ratio: reserve.max_ratio(&position.loan_type, config)?,
+        });
+    }
+
+    require!(reserve_accounts.next().is_none(), LendingError::WrongRemainingAccountSchema);
+
+    Ok(collaterals)
+}
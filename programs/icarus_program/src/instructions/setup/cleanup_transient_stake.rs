@@ -0,0 +1,143 @@
+pub use {
+    anchor_lang::prelude::*,
+    solana_program::{
+        stake::{self, state::StakeStateV2, program::ID as STAKE_PROGRAM_ID},
+        program::invoke_signed,
+        borsh1::try_from_slice_unchecked,
+    },
+    crate::{is_stake_inactive, state::{Versioned, Reserve, LendingMarket, TransientStakeKind}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, ACTIVATING_STAKE_PREFIX, DEACTIVATING_STAKE_PREFIX, RESERVE_STAKE_PREFIX},
+};
+
+/// Maximum number of transient stake accounts closed in a single call
+pub const MAX_TRANSIENT_STAKE_CLEANUP: usize = 8;
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct TransientStakeSeed {
+    pub kind: TransientStakeKind,
+    pub epoch: u64,
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct CleanupTransientStakeArgs {
+    /// Seeds of the transient stake accounts passed as remaining accounts, in the same order
+    pub transient_stakes: Vec<TransientStakeSeed>,
+}
+
+#[derive(Accounts)]
+pub struct CleanupTransientStake<'info> {
+    #[account(mut)]
+    pub cranker: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [RESERVE_PREFIX, reserve.lending_market.as_ref(), reserve.vote_account.as_ref()],
+        bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub reserve: Account<'info, Reserve>,
+    #[account(
+        seeds = [LENDING_MARKET_AUTHORITY_PREFIX, reserve.lending_market.as_ref()],
+        bump = lending_market.authority_bump,
+    )]
+    /// CHECK: PDA account, no need to validate
+    pub lending_market_authority: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [RESERVE_STAKE_PREFIX, reserve.key().as_ref()],
+        bump = reserve.stake_bump,
+    )]
+    /// CHECK: PDA account, no need to validate
+    pub reserve_stake: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    pub stake_history: Sysvar<'info, StakeHistory>,
+    #[account(address = STAKE_PROGRAM_ID)]
+    /// CHECK: checked by address constraint
+    pub stake_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>
+}
+
+impl<'info> CleanupTransientStake<'info> {
+    /// Close a spent transient stake account, returning its rent to the cranker and any stake
+    /// left in it to the Reserve stake. Returns the stake amount reclaimed
+    pub fn close_transient_stake(&self, transient_stake: &AccountInfo<'info>, seed: &TransientStakeSeed) -> Result<u64> {
+        // Only accounts opened by an earlier refresh and already settled by it can be closed
+        self.reserve.check_transient_stake_closable(seed.kind, seed.epoch)?;
+
+        let prefix = match seed.kind {
+            TransientStakeKind::Activating => ACTIVATING_STAKE_PREFIX,
+            TransientStakeKind::Deactivating => DEACTIVATING_STAKE_PREFIX,
+        };
+        let reserve_key = self.reserve.key();
+        let epoch = seed.epoch.to_le_bytes();
+        let (transient_key, _) = Pubkey::find_program_address(&[prefix, reserve_key.as_ref(), epoch.as_ref()], &crate::ID);
+        require_keys_eq!(transient_key, transient_stake.key(), LendingError::InvalidTransientStake);
+
+        // Stake accounts left initialized or fully deactivated, the rest was never opened or is still in use
+        require_keys_eq!(*transient_stake.owner, STAKE_PROGRAM_ID, LendingError::InvalidTransientStake);
+        let rent_exempt_reserve = match try_from_slice_unchecked::<StakeStateV2>(&transient_stake.data.borrow())? {
+            StakeStateV2::Initialized(meta) => meta.rent_exempt_reserve,
+            StakeStateV2::Stake(meta, stake, _) => {
+                require!(is_stake_inactive(&stake, self.clock.epoch, &self.stake_history), LendingError::TransientStakeInUse);
+                meta.rent_exempt_reserve
+            },
+            StakeStateV2::Uninitialized | StakeStateV2::RewardsPool => return Err(LendingError::InvalidTransientStake.into()),
+        };
+
+        let lamports = transient_stake.lamports();
+        let rent = rent_exempt_reserve.min(lamports);
+        let stake_amount = lamports - rent;
+
+        self.withdraw_transient_stake(transient_stake, &self.reserve_stake.to_account_info(), stake_amount)?;
+        self.withdraw_transient_stake(transient_stake, &self.cranker.to_account_info(), rent)?;
+
+        Ok(stake_amount)
+    }
+
+    fn withdraw_transient_stake(&self, transient_stake: &AccountInfo<'info>, recipient: &AccountInfo<'info>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let lending_market_key = self.reserve.lending_market;
+        let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
+
+        invoke_signed(
+            &stake::instruction::withdraw(transient_stake.key, &self.lending_market_authority.key(), recipient.key, amount, None),
+            &[
+                transient_stake.clone(),
+                recipient.clone(),
+                self.clock.to_account_info(),
+                self.stake_history.to_account_info(),
+                self.lending_market_authority.to_account_info(),
+            ],
+            &[&authority_seeds[..]],
+        )?;
+
+        Ok(())
+    }
+}
+
+pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, CleanupTransientStake<'info>>, args: CleanupTransientStakeArgs) -> Result<()> {
+    let remaining_accounts = ctx.remaining_accounts;
+
+    require_eq!(
+        remaining_accounts.len(),
+        args.transient_stakes.len(),
+        LendingError::WrongRemainingAccountSchema
+    );
+    require_gte!(MAX_TRANSIENT_STAKE_CLEANUP, remaining_accounts.len(), LendingError::WrongRemainingAccountSchema);
+
+    let mut reclaimed_amount: u64 = 0;
+    for (transient_stake, seed) in remaining_accounts.iter().zip(args.transient_stakes.iter()) {
+        let stake_amount = ctx.accounts.close_transient_stake(transient_stake, seed)?;
+        reclaimed_amount = reclaimed_amount.checked_add(stake_amount).ok_or(LendingError::MathOverflow)?;
+    }
+
+    // Stake left in spent accounts came out of the reserve stake and is already counted in the
+    // collateral, so it goes back there without being measured as rewards
+    ctx.accounts.reserve.rewards.record_returned_stake(reclaimed_amount)?;
+
+    Ok(())
+}
@@ -1,15 +1,23 @@
 pub use {
     anchor_lang::prelude::*,
-    anchor_spl::token::{Mint, Token, TokenAccount, mint_to, MintTo},
-    solana_program::{system_instruction, program::{invoke, invoke_signed}, stake::{self, config::ID as STAKE_CONFIG_ID, program::ID as STAKE_PROGRAM_ID}, native_token::LAMPORTS_PER_SOL},
-    crate::{state::{LendingMarket, Reserve, InitReserveParams, ReserveLiquidity, NewReserveLiquidityParams, NewReserveCollateralParams, ReserveCollateral}, error::LendingError, LIQUIDITY_VAULT_PREFIX, RESERVE_STAKE_PREFIX, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, COLLATERAL_MINT_PREFIX, LIQUIDITY_MINT_PREFIX, MINT_DECIMALS},
+    anchor_spl::token_interface::{
+        TokenInterface, initialize_mint2, InitializeMint2, metadata_pointer_initialize, MetadataPointerInitialize, token_metadata_initialize, TokenMetadataInitialize,
+        spl_token_2022::{self, extension::ExtensionType},
+        spl_token_metadata_interface::state::TokenMetadata,
+        spl_pod::optional_keys::OptionalNonZeroPubkey,
+    },
+    solana_program::{system_instruction, program::{invoke, invoke_signed}, program_pack::Pack, stake::{self, config::ID as STAKE_CONFIG_ID, program::ID as STAKE_PROGRAM_ID}, vote::program::ID as VOTE_PROGRAM_ID, native_token::LAMPORTS_PER_SOL},
+    crate::{get_stake_amount, get_vote_state, state::{Versioned, LendingMarket, Reserve, ReserveMint, InitReserveParams, ReserveLiquidity, NewReserveLiquidityParams, NewReserveCollateralParams, ReserveCollateral, ReserveValidator, NewReserveValidatorParams}, error::LendingError, LIQUIDITY_VAULT_PREFIX, RESERVE_STAKE_PREFIX, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX, COLLATERAL_MINT_PREFIX, LIQUIDITY_MINT_PREFIX, MINT_DECIMALS},
 };
 
 #[derive(Accounts)]
 pub struct InitializeReserve<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
-    #[account(has_one = owner @ LendingError::OwnerMismatch)]
+    #[account(
+        has_one = owner @ LendingError::OwnerMismatch,
+        constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
         init,
@@ -25,7 +33,8 @@ pub struct InitializeReserve<'info> {
     )]
     /// CHECK: todo
     pub lending_market_authority: UncheckedAccount<'info>,
-    /// CHECK: todo
+    #[account(owner = VOTE_PROGRAM_ID @ LendingError::InvalidVoteAccount)]
+    /// CHECK: checked by the owner constraint, vote state validated in the handler
     pub vote_account: UncheckedAccount<'info>,
     #[account(
         mut,
@@ -41,19 +50,19 @@ pub struct InitializeReserve<'info> {
     /// CHECK: todo
     pub reserve_vault: UncheckedAccount<'info>,
     #[account(
-        init,
-        payer = owner,
-        mint::decimals = MINT_DECIMALS,
-        mint::authority = lending_market_authority,
+        mut,
+        seeds = [COLLATERAL_MINT_PREFIX, reserve.key().as_ref()],
+        bump,
     )]
-    pub collateral_mint: Box<Account<'info, Mint>>,
+    /// CHECK: created in the handler with the token program passed
+    pub collateral_mint: UncheckedAccount<'info>,
     #[account(
-        init,
-        payer = owner,
-        mint::decimals = MINT_DECIMALS,
-        mint::authority = lending_market_authority,
+        mut,
+        seeds = [LIQUIDITY_MINT_PREFIX, reserve.key().as_ref()],
+        bump,
     )]
-    pub liquidity_mint: Box<Account<'info, Mint>>,
+    /// CHECK: created in the handler with the token program passed
+    pub liquidity_mint: UncheckedAccount<'info>,
     pub rent: Sysvar<'info, Rent>,
     pub clock: Sysvar<'info, Clock>,
     pub stake_history: Sysvar<'info, StakeHistory>,
@@ -63,11 +72,99 @@ pub struct InitializeReserve<'info> {
     #[account(address = STAKE_PROGRAM_ID)]
     /// CHECK: checked by the address constraint
     pub stake_program: UncheckedAccount<'info>,
-    pub token_program: Program<'info, Token>,
+    /// Token-2022 creates the mints with on-chain metadata, the legacy Token program without
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>
 }
 
 impl <'info> InitializeReserve<'info> {
+    pub fn initialize_mint(&self, mint: &AccountInfo<'info>, reserve_mint: ReserveMint, mint_prefix: &[u8], mint_bump: u8) -> Result<()> {
+        let reserve_key = self.reserve.key();
+        let mint_seeds = &[mint_prefix, reserve_key.as_ref(), &[mint_bump]];
+        let mint_signers = &[&mint_seeds[..]];
+
+        let lending_market_key = self.lending_market.key();
+        let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
+        let authority_signers = &[&authority_seeds[..]];
+
+        // Only Token-2022 mints carry the metadata extensions
+        let with_metadata = self.token_program.key() == spl_token_2022::ID;
+        let metadata = TokenMetadata {
+            update_authority: OptionalNonZeroPubkey(self.lending_market_authority.key()),
+            mint: mint.key(),
+            name: reserve_mint.token_name(&lending_market_key, &self.vote_account.key()),
+            symbol: reserve_mint.token_symbol(&self.vote_account.key()),
+            ..Default::default()
+        };
+
+        let (mint_space, metadata_space) = if with_metadata {
+            (
+                ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[ExtensionType::MetadataPointer])?,
+                metadata.tlv_size_of()?,
+            )
+        } else {
+            (spl_token_2022::state::Mint::LEN, 0)
+        };
+
+        // Fund the metadata up front, Token-2022 reallocs the mint when it gets initialized
+        invoke_signed(
+            &system_instruction::create_account(
+                self.owner.key,
+                mint.key,
+                self.rent.minimum_balance(mint_space + metadata_space),
+                mint_space as u64,
+                self.token_program.key,
+            ),
+            &[self.owner.to_account_info(), mint.clone()],
+            mint_signers,
+        )?;
+
+        if with_metadata {
+            metadata_pointer_initialize(
+                CpiContext::new(
+                    self.token_program.to_account_info(),
+                    MetadataPointerInitialize {
+                        token_program_id: self.token_program.to_account_info(),
+                        mint: mint.clone(),
+                    },
+                ),
+                Some(self.lending_market_authority.key()),
+                Some(mint.key()),
+            )?;
+        }
+
+        initialize_mint2(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                InitializeMint2 { mint: mint.clone() },
+            ),
+            MINT_DECIMALS,
+            self.lending_market_authority.key,
+            None,
+        )?;
+
+        if with_metadata {
+            token_metadata_initialize(
+                CpiContext::new_with_signer(
+                    self.token_program.to_account_info(),
+                    TokenMetadataInitialize {
+                        token_program_id: self.token_program.to_account_info(),
+                        metadata: mint.clone(),
+                        update_authority: self.lending_market_authority.to_account_info(),
+                        mint_authority: self.lending_market_authority.to_account_info(),
+                        mint: mint.clone(),
+                    },
+                    authority_signers,
+                ),
+                metadata.name,
+                metadata.symbol,
+                metadata.uri,
+            )?;
+        }
+
+        Ok(())
+    }
+
     pub fn initialize_stake_account(&mut self, stake_bump: u8) -> Result<()> {
         let reserve_key = self.reserve.key();
         let stake_seeds = &[RESERVE_STAKE_PREFIX, reserve_key.as_ref(), &[stake_bump]];
@@ -143,7 +240,9 @@ impl <'info> InitializeReserve<'info> {
 }
 
 pub fn handler<'info>(ctx: Context<InitializeReserve>) -> Result<()> {
-    // CHECKS: todo
+    // Validate the validator against the market eligibility rules
+    let vote_state = get_vote_state(&ctx.accounts.vote_account.to_account_info())?;
+    ctx.accounts.lending_market.config.check_validator_eligibility(&vote_state, &ctx.accounts.clock)?;
     
     // Initialize Reserve State
     ctx.accounts.reserve.init(InitReserveParams {
@@ -159,13 +258,30 @@ pub fn handler<'info>(ctx: Context<InitializeReserve>) -> Result<()> {
             mint_pubkey: ctx.accounts.collateral_mint.key(), 
             stake_account: ctx.accounts.reserve_stake.key()
         }),
+        validator: ReserveValidator::new(NewReserveValidatorParams{
+            commission: vote_state.commission,
+            current_epoch: ctx.accounts.clock.epoch,
+        }),
         bump: ctx.bumps.reserve,
         stake_bump: ctx.bumps.reserve_stake,
         vault_bump: ctx.bumps.reserve_vault,
+        liquidity_mint_bump: ctx.bumps.liquidity_mint,
+        collateral_mint_bump: ctx.bumps.collateral_mint,
     });
 
+    // Initialize Liquidity and Collateral Mints
+    let liquidity_mint = ctx.accounts.liquidity_mint.to_account_info();
+    ctx.accounts.initialize_mint(&liquidity_mint, ReserveMint::Liquidity, LIQUIDITY_MINT_PREFIX, ctx.bumps.liquidity_mint)?;
+    let collateral_mint = ctx.accounts.collateral_mint.to_account_info();
+    ctx.accounts.initialize_mint(&collateral_mint, ReserveMint::Collateral, COLLATERAL_MINT_PREFIX, ctx.bumps.collateral_mint)?;
+
     // Initialize Stake Account
     ctx.accounts.initialize_stake_account(ctx.bumps.reserve_stake)?;
 
+    // Snapshot the reserve stake to start measuring rewards
+    let stake_lamports = ctx.accounts.reserve_stake.lamports();
+    let delegated_stake = get_stake_amount(&ctx.accounts.reserve_stake.to_account_info())?;
+    ctx.accounts.reserve.rewards.snapshot(stake_lamports, delegated_stake);
+
     Ok(())
 }
@@ -1,64 +1,106 @@
 use {
     anchor_lang::prelude::*,
+    anchor_spl::token_interface::TokenInterface,
     crate::{
-        state::{LendingMarket, Reserve, Obligation},
+        resize_account, ObligationCollateralToken,
+        state::{Versioned, LendingMarket, Reserve, Obligation},
         error::LendingError, 
         OBLIGATION_PREFIX
     },
 };
 
+/// Accounts passed per liquidated position: the reserve, its collateral mint and the
+/// collateral token account of the obligation
+pub const LIQUIDATED_POSITION_ACCOUNTS: usize = 3;
+
 #[derive(Accounts)]
 pub struct LiquidatePosition<'info> {
     #[account(mut)]
     pub cranker: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
         mut,
         has_one = lending_market @LendingError::LendingMarketMismatch,
-        seeds = [OBLIGATION_PREFIX, obligation.owner.as_ref()],
+        seeds = [OBLIGATION_PREFIX, obligation.creator.as_ref()],
         bump,
+        constraint = obligation.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub obligation: Account<'info, Obligation>,
+    #[account(mut, address = obligation.owner @ LendingError::ObligationAuthorityMismatch)]
+    /// Receives the rent freed by the removed positions and their collateral token accounts
+    pub obligation_owner: SystemAccount<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>
 }
 
 impl<'info> LiquidatePosition<'info> {
-    pub fn liquidate_position(&mut self, obligation: &mut Obligation, reserve_account: &AccountInfo<'info>) -> Result<()> {
-
-        let reserve_data = reserve_account.try_borrow_data()?;
-        let mut reserve = Reserve::try_deserialize(&mut &reserve_data[..])
+    pub fn liquidate_position(&mut self, position_accounts: &[AccountInfo<'info>]) -> Result<()> {
+        let (reserve_account, collateral_mint, collateral_token) = (&position_accounts[0], &position_accounts[1], &position_accounts[2]);
+        require_keys_eq!(*reserve_account.owner, crate::ID, LendingError::InvalidReserveAccount);
+        require!(reserve_account.is_writable, LendingError::InvalidReserveAccount);
+        let mut reserve = Reserve::try_deserialize(&mut &reserve_account.try_borrow_data()?[..])
             .map_err(|_| LendingError::InvalidReserveAccount)?;
+        require!(reserve.is_current_version(), LendingError::AccountNotMigrated);
+        require_keys_eq!(reserve.lending_market, self.lending_market.key(), LendingError::LendingMarketMismatch);
+        require_keys_eq!(collateral_mint.key(), reserve.collateral.mint_pubkey, LendingError::InvalidReserveAccount);
 
+        // Pending collateral counts once the reserve is refreshed in a later epoch
         let vote_account = reserve.vote_account;
+        self.obligation.find_position_mut(vote_account)?.settle_pending(reserve.last_epoch)?;
 
-        let (position, _) = obligation.find_position(vote_account)?;
-
+        // The position is removed from the obligation so keep a copy
+        let position = self.obligation.find_position(vote_account)?.0.clone();
         require!(
-            self.obligation.repay_or_liquidate(vote_account, Clock::get()?.epoch)?.0,
+            self.obligation.repay_or_liquidate(vote_account, Clock::get()?.epoch, &self.lending_market.config)?.0,
             LendingError::NotLiquidatable
         );
 
-        reserve.collateral.repay_or_liquidate(position.deposited_amount, position.deposited_amount, position.weighted_deposited_amount)?;
+        let deposited_amount = position.total_deposited_amount()?;
+        reserve.collateral.repay_or_liquidate(deposited_amount, deposited_amount, position.borrowed_amount, position.total_weighted_deposited_amount()?)?;
+        reserve.last_update.mark_stale();
+
+        reserve.try_serialize(&mut &mut reserve_account.try_borrow_mut_data()?[..])?;
+
+        // Burn the Collateral Tokens held for the position, the recorded supply already dropped
+        let creator = self.obligation.creator;
+        let obligation_seeds = &[OBLIGATION_PREFIX, creator.as_ref(), &[self.obligation.bump]];
+        ObligationCollateralToken {
+            token_account: collateral_token,
+            obligation: &self.obligation.to_account_info(),
+            obligation_seeds,
+            collateral_mint,
+            token_program: &self.token_program.to_account_info(),
+        }.close(&self.obligation_owner.to_account_info())?;
 
         Ok(())
-    }           
+    }
 }
 
 pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, LiquidatePosition<'info>>) -> Result<()> {
-    // CHECKS: todo
-
+    // The remaining accounts are the writable reserves of the positions to liquidate, each
+    // followed by its collateral mint and the collateral token account of the obligation
     let remaining_accounts = ctx.remaining_accounts;
-    let mut obligation = ctx.accounts.obligation.clone();
 
     require_eq!(
         remaining_accounts.len(), 
-        obligation.positions.len(), 
+        ctx.accounts.obligation.positions.len() * LIQUIDATED_POSITION_ACCOUNTS, 
         LendingError::WrongRemainingAccountSchema
     );
 
-    for reserve_account in remaining_accounts {
-        ctx.accounts.liquidate_position(&mut obligation, reserve_account)?;
+    for position_accounts in remaining_accounts.chunks(LIQUIDATED_POSITION_ACCOUNTS) {
+        ctx.accounts.liquidate_position(position_accounts)?;
     }
+
+    // Shrink the obligation and refund the rent of the removed positions to the owner
+    let obligation_space = Obligation::space(ctx.accounts.obligation.positions.len());
+    resize_account(
+        &ctx.accounts.obligation.to_account_info(),
+        &ctx.accounts.obligation_owner.to_account_info(),
+        &ctx.accounts.obligation_owner.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+        obligation_space,
+    )?;
         
     Ok(())
-}
\ No newline at end of file
+}
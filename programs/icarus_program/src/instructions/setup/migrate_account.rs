@@ -0,0 +1,116 @@
+pub use {
+    anchor_lang::{prelude::*, Discriminator},
+    crate::{
+        get_stake_amount, get_vote_state, resize_account,
+        state::{LendingMarket, Reserve, Obligation, ReserveValidator, NewReserveValidatorParams, PROGRAM_VERSION, UNINITIALIZED_VERSION},
+        error::LendingError,
+    },
+};
+
+#[derive(Accounts)]
+pub struct MigrateAccount<'info> {
+    /// Pays the rent when the new layout is bigger
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// Receives the rent freed when the new layout is smaller: the owner of an obligation, or the
+    /// owner of the lending market for markets and reserves
+    #[account(mut)]
+    /// CHECK: checked against the owner of the migrated account in the handler
+    pub rent_receiver: UncheckedAccount<'info>,
+    #[account(mut, owner = crate::ID @ LendingError::InvalidAccountType)]
+    /// CHECK: the layout is read from the discriminator and the version
+    pub account: UncheckedAccount<'info>,
+    pub clock: Sysvar<'info, Clock>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> MigrateAccount<'info> {
+    /// Resize the account for the new layout and write it
+    fn write_account<T: AccountSerialize>(&self, migrated: &T, space: usize) -> Result<()> {
+        resize_account(
+            &self.account.to_account_info(),
+            &self.payer.to_account_info(),
+            &self.rent_receiver.to_account_info(),
+            &self.system_program.to_account_info(),
+            space,
+        )?;
+
+        let mut data = self.account.try_borrow_mut_data()?;
+        migrated.try_serialize(&mut &mut data[..])?;
+
+        Ok(())
+    }
+
+    /// Fill the Reserve fields added in version 2 from the vote and stake accounts, the lending
+    /// market is passed to check the rent receiver against its owner
+    fn complete_reserve_migration(&self, reserve: &mut Reserve, remaining_accounts: &[AccountInfo<'info>]) -> Result<()> {
+        require_eq!(remaining_accounts.len(), 3, LendingError::WrongRemainingAccountSchema);
+        let (vote_account, reserve_stake, lending_market) = (&remaining_accounts[0], &remaining_accounts[1], &remaining_accounts[2]);
+        require_keys_eq!(vote_account.key(), reserve.vote_account, LendingError::InvalidVoteAccount);
+        require_keys_eq!(reserve_stake.key(), reserve.collateral.stake_account, LendingError::InvalidReserveAccount);
+        require_keys_eq!(lending_market.key(), reserve.lending_market, LendingError::LendingMarketMismatch);
+        require_keys_eq!(self.rent_receiver.key(), get_lending_market_owner(lending_market)?, LendingError::OwnerMismatch);
+
+        // Version 1 mints were created from keypairs, they are kept and checked by address
+        reserve.set_mint_bumps(&self.account.key());
+
+        let vote_state = get_vote_state(vote_account)?;
+        reserve.validator = ReserveValidator::new(NewReserveValidatorParams {
+            commission: vote_state.commission,
+            current_epoch: self.clock.epoch,
+        });
+
+        // Start measuring rewards from the current state of the reserve stake
+        reserve.rewards.snapshot(reserve_stake.lamports(), get_stake_amount(reserve_stake)?);
+
+        Ok(())
+    }
+}
+
+/// Owner of a lending market, which may not be migrated yet
+fn get_lending_market_owner(lending_market: &AccountInfo) -> Result<Pubkey> {
+    require_keys_eq!(*lending_market.owner, crate::ID, LendingError::InvalidAccountType);
+    let data = lending_market.try_borrow_data()?;
+    require!(data.len() > 8 && data[..8] == LendingMarket::DISCRIMINATOR, LendingError::InvalidAccountType);
+
+    let lending_market = match data[8] {
+        PROGRAM_VERSION => LendingMarket::try_deserialize(&mut &data[..])?,
+        version => LendingMarket::migrate(version, &data[8..])?,
+    };
+
+    Ok(lending_market.owner)
+}
+
+pub fn handler<'info>(ctx: Context<'_, '_, '_, 'info, MigrateAccount<'info>>) -> Result<()> {
+    let (discriminator, version, data) = {
+        let data = ctx.accounts.account.try_borrow_data()?;
+        require_gt!(data.len(), 8, LendingError::InvalidAccountType);
+        let mut discriminator = [0u8; 8];
+        discriminator.copy_from_slice(&data[..8]);
+        (discriminator, data[8], data[8..].to_vec())
+    };
+
+    require_neq!(version, UNINITIALIZED_VERSION, LendingError::UnsupportedAccountVersion);
+    require_gt!(PROGRAM_VERSION, version, LendingError::AccountAlreadyMigrated);
+
+    if discriminator == LendingMarket::DISCRIMINATOR {
+        let lending_market = LendingMarket::migrate(version, &data)?;
+        require_keys_eq!(ctx.accounts.rent_receiver.key(), lending_market.owner, LendingError::OwnerMismatch);
+        ctx.accounts.write_account(&lending_market, LendingMarket::INIT_SPACE)?;
+    } else if discriminator == Reserve::DISCRIMINATOR {
+        let mut reserve = Reserve::migrate(version, &data)?;
+        ctx.accounts.complete_reserve_migration(&mut reserve, ctx.remaining_accounts)?;
+        ctx.accounts.write_account(&reserve, Reserve::INIT_SPACE)?;
+    } else if discriminator == Obligation::DISCRIMINATOR {
+        let obligation = Obligation::migrate(version, &data)?;
+        require_keys_eq!(ctx.accounts.rent_receiver.key(), obligation.owner, LendingError::ObligationAuthorityMismatch);
+        let space = Obligation::space(obligation.positions.len());
+        ctx.accounts.write_account(&obligation, space)?;
+    } else {
+        return Err(LendingError::InvalidAccountType.into());
+    }
+
+    msg!("Account migrated from version {} to {}", version, PROGRAM_VERSION);
+
+    Ok(())
+}
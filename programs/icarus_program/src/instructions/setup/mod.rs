@@ -1,16 +1,26 @@
 pub mod init_lending_market;
 pub mod set_lending_market_owner;
+pub mod set_lending_market_config;
 pub mod init_reserve;
+pub mod update_reserve_token_metadata;
+pub mod set_reserve_fees;
 
 pub use init_lending_market::*;
 pub use set_lending_market_owner::*;
+pub use set_lending_market_config::*;
 pub use init_reserve::*;
+pub use update_reserve_token_metadata::*;
+pub use set_reserve_fees::*;
 
 pub mod refresh_reserve;
 pub mod refresh_reserve_epoch;
 pub mod liquidate_position;
+pub mod cleanup_transient_stake;
+pub mod migrate_account;
 
 pub use refresh_reserve::*;
 pub use refresh_reserve_epoch::*;
 pub use liquidate_position::*;
+pub use cleanup_transient_stake::*;
+pub use migrate_account::*;
 
@@ -1,6 +1,6 @@
 pub use {
     anchor_lang::prelude::*,
-    crate::{state::Reserve, RESERVE_PREFIX},
+    crate::{error::LendingError, state::{Reserve, Versioned}, RESERVE_PREFIX},
 };
 
 #[derive(Accounts)]
@@ -11,6 +11,7 @@ pub struct RefreshReserve<'info> {
         mut,
         seeds = [RESERVE_PREFIX, reserve.lending_market.as_ref(), reserve.vote_account.as_ref()],
         bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub reserve: Account<'info, Reserve>,
 }
@@ -1,28 +1,31 @@
 pub use {
     anchor_lang::prelude::*,
     solana_program::{
-        stake::{self, state::StakeStateV2, config::ID as STAKE_CONFIG_ID, program::ID as STAKE_PROGRAM_ID},
-        program::invoke_signed,
-        borsh1::try_from_slice_unchecked,
+        stake::{self, config::ID as STAKE_CONFIG_ID, program::ID as STAKE_PROGRAM_ID},
+        vote::program::ID as VOTE_PROGRAM_ID,
+        program::{invoke, invoke_signed},
         system_instruction,
         native_token::LAMPORTS_PER_SOL,
     },
-    crate::{state::{Reserve, LendingMarket}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_STAKE_PREFIX, RESERVE_PREFIX, ACTIVATING_STAKE_PREFIX, DEACTIVATING_STAKE_PREFIX, LIQUIDITY_VAULT_PREFIX},
+    crate::{get_stake_amount, get_stake_state, get_vote_state, is_stake_active, is_stake_inactive, state::{Versioned, Reserve, LendingMarket, EpochRefreshParams}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_STAKE_PREFIX, RESERVE_PREFIX, ACTIVATING_STAKE_PREFIX, DEACTIVATING_STAKE_PREFIX, LIQUIDITY_VAULT_PREFIX},
 };
 
 #[derive(Accounts)]
 pub struct RefreshEpoch<'info> {
     #[account(mut)]
     pub cranker: Signer<'info>,
+    #[account(constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated)]
     pub lending_market: Account<'info, LendingMarket>,
     #[account(
         mut,
         has_one = lending_market,
         seeds = [RESERVE_PREFIX, reserve.lending_market.key().as_ref(), vote_account.key().as_ref()],
         bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub reserve: Account<'info, Reserve>,
-    /// CHECK: todo
+    #[account(owner = VOTE_PROGRAM_ID @ LendingError::InvalidVoteAccount)]
+    /// CHECK: checked by the owner constraint and the reserve seeds
     pub vote_account: UncheckedAccount<'info>,
     #[account(
         seeds = [LENDING_MARKET_AUTHORITY_PREFIX, reserve.lending_market.as_ref()],
@@ -38,35 +41,36 @@ pub struct RefreshEpoch<'info> {
     /// CHECK: todo
     pub reserve_stake: UncheckedAccount<'info>,
     #[account(
+        mut,
         seeds = [LIQUIDITY_VAULT_PREFIX, reserve.key().as_ref()],
-        bump,
+        bump = reserve.vault_bump,
     )]
     /// CHECK: todo
     pub reserve_vault: UncheckedAccount<'info>,
     #[account(
         mut,
-        seeds = [ACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), reserve.last_epoch.to_le_bytes().as_ref()],
+        seeds = [ACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), reserve.activating_stake_seed().as_ref()],
         bump,
     )]
     /// CHECK: todo
     pub old_activating_reserve_stake: UncheckedAccount<'info>,
     #[account(
         mut,
-        seeds = [ACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), (reserve.last_epoch + 1).to_le_bytes().as_ref()],
+        seeds = [ACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), clock.epoch.to_le_bytes().as_ref()],
         bump,
     )]
     /// CHECK: todo
     pub new_activating_reserve_stake: UncheckedAccount<'info>,
     #[account(
         mut,
-        seeds = [DEACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), reserve.last_epoch.to_le_bytes().as_ref()],
+        seeds = [DEACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), reserve.deactivating_stake_seed().as_ref()],
         bump,
     )]
     /// CHECK: todo
     pub old_deactivating_reserve_stake: UncheckedAccount<'info>,
     #[account(
         mut,
-        seeds = [DEACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), (reserve.last_epoch + 1).to_le_bytes().as_ref()],
+        seeds = [DEACTIVATING_STAKE_PREFIX, reserve.key().as_ref(), clock.epoch.to_le_bytes().as_ref()],
         bump,
     )]
     /// CHECK: todo
@@ -84,13 +88,47 @@ pub struct RefreshEpoch<'info> {
 }
 
 impl <'info> RefreshEpoch<'info> {
-    /// Get the stake amount from the stake account
-    pub fn get_stake_amount(&mut self) -> Result<u64> {
-        let stake_state = try_from_slice_unchecked::<StakeStateV2>(&self.reserve_stake.data.borrow())?;
+    /// Get the lamports of the reserve stake account that are neither delegated nor reserved for rent
+    pub fn get_inactive_stake_amount(&self) -> Result<u64> {
+        let (meta, stake) = get_stake_state(&self.reserve_stake.to_account_info())?;
+
+        self.reserve_stake.lamports()
+            .checked_sub(stake.delegation.stake)
+            .and_then(|v| v.checked_sub(meta.rent_exempt_reserve))
+            .ok_or(LendingError::MathOverflow.into())
+    }
+
+    /// Check if the reserve stake account is fully active, so transient accounts can be merged into it
+    pub fn is_reserve_stake_active(&self) -> Result<bool> {
+        let (_, stake) = get_stake_state(&self.reserve_stake.to_account_info())?;
+        Ok(is_stake_active(&stake, self.clock.epoch, &self.stake_history))
+    }
+
+    /// Check if the old activating stake account finished warming up
+    pub fn is_old_activating_stake_active(&self) -> bool {
+        match get_stake_state(&self.old_activating_reserve_stake.to_account_info()) {
+            Ok((_, stake)) => is_stake_active(&stake, self.clock.epoch, &self.stake_history),
+            Err(_) => false,
+        }
+    }
 
-        match stake_state {
-            StakeStateV2::Stake(_, stake, _) => Ok(stake.delegation.stake),
-            _ => Err(LendingError::WrongStakeStake.into()),
+    /// Check if the old deactivating stake account finished cooling down
+    pub fn is_old_deactivating_stake_inactive(&self) -> bool {
+        match get_stake_state(&self.old_deactivating_reserve_stake.to_account_info()) {
+            Ok((_, stake)) => is_stake_inactive(&stake, self.clock.epoch, &self.stake_history),
+            Err(_) => false,
+        }
+    }
+
+    /// Get the rent-exempt reserve of the old deactivating stake account and the stake above it
+    pub fn get_deactivated_stake_amounts(&self) -> (u64, u64) {
+        match get_stake_state(&self.old_deactivating_reserve_stake.to_account_info()) {
+            Ok((meta, _)) => {
+                let lamports = self.old_deactivating_reserve_stake.lamports();
+                let rent = meta.rent_exempt_reserve.min(lamports);
+                (rent, lamports - rent)
+            },
+            Err(_) => (0, 0),
         }
     }
 
@@ -115,12 +153,38 @@ impl <'info> RefreshEpoch<'info> {
         Ok(())
     }
 
-    /// 
-    pub fn split_and_deactivate_amount(&self, amount: u64) -> Result<()> {
+    /// Split the amount to claim from the Lending Market Stake account into a new stake account and deactivate it
+    pub fn split_and_deactivate_amount(&self, amount: u64, stake_space: usize, stake_bump: u8) -> Result<()> {
+        let reserve_key = self.reserve.key();
+        let current_epoch = self.clock.epoch.to_le_bytes();
+        let stake_seeds = &[DEACTIVATING_STAKE_PREFIX, reserve_key.as_ref(), current_epoch.as_ref(), &[stake_bump]];
+        let stake_signers = &[&stake_seeds[..]];
+
         let lending_market_key = self.reserve.lending_market;
         let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
         let signers = &[&authority_seeds[..]];
 
+        // The cranker fronts the rent so the whole amount stays staked, it gets it back on claim
+        invoke(
+            &system_instruction::transfer(self.cranker.key, self.new_deactivating_reserve_stake.key, self.rent.minimum_balance(stake_space)),
+            &[
+                self.cranker.to_account_info(),
+                self.new_deactivating_reserve_stake.to_account_info(),
+            ],
+        )?;
+
+        invoke_signed(
+            &system_instruction::allocate(self.new_deactivating_reserve_stake.key, stake_space as u64),
+            &[self.new_deactivating_reserve_stake.to_account_info()],
+            stake_signers,
+        )?;
+
+        invoke_signed(
+            &system_instruction::assign(self.new_deactivating_reserve_stake.key, self.stake_program.key),
+            &[self.new_deactivating_reserve_stake.to_account_info()],
+            stake_signers,
+        )?;
+
         invoke_signed(
             stake::instruction::split(&self.reserve_stake.key(), &self.lending_market_authority.key(), amount, &self.new_deactivating_reserve_stake.key()).last().unwrap(),
             &[
@@ -144,17 +208,27 @@ impl <'info> RefreshEpoch<'info> {
         Ok(())
     }
 
-    /// 
-    pub fn claim_deactivated_stake_amount(&self) -> Result<()> {
+    /// Withdraw the stake of the fully deactivated stake account into the Reserve vault,
+    /// and its rent to the cranker, closing it
+    pub fn claim_deactivated_stake_amount(&self, rent: u64, stake_amount: u64) -> Result<()> {
+        self.withdraw_deactivated_stake(&self.reserve_vault.to_account_info(), stake_amount)?;
+        self.withdraw_deactivated_stake(&self.cranker.to_account_info(), rent)
+    }
+
+    fn withdraw_deactivated_stake(&self, recipient: &AccountInfo<'info>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
         let lending_market_key = self.reserve.lending_market;
         let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
         let signers = &[&authority_seeds[..]];
 
         invoke_signed(
-            &stake::instruction::withdraw(&self.old_deactivating_reserve_stake.key(), &self.lending_market_authority.key(), &self.reserve_vault.key(), self.old_deactivating_reserve_stake.lamports(), None),
+            &stake::instruction::withdraw(&self.old_deactivating_reserve_stake.key(), &self.lending_market_authority.key(), recipient.key, amount, None),
             &[
                 self.old_deactivating_reserve_stake.to_account_info(), 
-                self.reserve_vault.to_account_info(), 
+                recipient.clone(), 
                 self.clock.to_account_info(),
                 self.stake_history.to_account_info(),
                 self.lending_market_authority.to_account_info(),
@@ -168,16 +242,26 @@ impl <'info> RefreshEpoch<'info> {
     /// Initialize & Delegate a new stake account from the Lending Market Stake account that has inactive lamports
     pub fn initialize_stake_account(&mut self, amount: u64, stake_space: usize, stake_bump: u8) -> Result<()> {
         let reserve_key = self.reserve.key();
-        let next_epoch = (self.reserve.last_epoch + 1).to_le_bytes();
-        let stake_seeds = &[ACTIVATING_STAKE_PREFIX, reserve_key.as_ref(), next_epoch.as_ref(), &[stake_bump]];
+        let current_epoch = self.clock.epoch.to_le_bytes();
+        let stake_seeds = &[ACTIVATING_STAKE_PREFIX, reserve_key.as_ref(), current_epoch.as_ref(), &[stake_bump]];
         let stake_signers = &[&stake_seeds[..]];
 
         let lending_market_key = self.reserve.lending_market;
         let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
         let stake_authority_signers = &[&authority_seeds[..]];
 
-        **self.reserve_stake.to_account_info().try_borrow_mut_lamports()? -= amount;
-        **self.new_activating_reserve_stake.to_account_info().try_borrow_mut_lamports()? += amount;
+        // The reserve stake account is owned by the stake program, so the excess is moved with a withdraw
+        invoke_signed(
+            &stake::instruction::withdraw(&self.reserve_stake.key(), &self.lending_market_authority.key(), &self.new_activating_reserve_stake.key(), amount, None),
+            &[
+                self.reserve_stake.to_account_info(),
+                self.new_activating_reserve_stake.to_account_info(),
+                self.clock.to_account_info(),
+                self.stake_history.to_account_info(),
+                self.lending_market_authority.to_account_info(),
+            ],
+            stake_authority_signers,
+        )?;
 
         let authorized = stake::state::Authorized::auto(self.lending_market_authority.key);
 
@@ -232,51 +316,66 @@ impl <'info> RefreshEpoch<'info> {
 pub fn handler<'info>(ctx: Context<RefreshEpoch>) -> Result<()> {
     // CHECKS: todo
     
-    // Accrues Interest
-    // ctx.accounts.reserve.accrue_interest(ctx.accounts.clock.epoch)?;
-
     // Update the Slot
     ctx.accounts.reserve.last_update.update_slot(Clock::get()?.slot);
 
-    let mut reserve = ctx.accounts.reserve.clone();
-
-    // Update the Epoch
-    if reserve.epoch_elapsed(Clock::get()?.epoch)? != 0 {
-        // Update the Reserve Epoch
-        reserve.update_epoch(ctx.accounts.reserve.last_epoch + 1);
-
-        let minimum_delegation = std::cmp::max(
-            stake::tools::get_minimum_delegation()?,
-            LAMPORTS_PER_SOL,
-        );
-        let stake_space = std::mem::size_of::<stake::state::StakeStateV2>();
-        let stake_rent_plus_initial = ctx.accounts.rent
-            .minimum_balance(stake_space)
-            .saturating_add(minimum_delegation);
-
-        let stake_amount = ctx.accounts.get_stake_amount()?;
-
-        let inactive_stake_amount = ctx.accounts.reserve_stake.lamports()
-            .checked_sub(stake_amount)
-            .ok_or(LendingError::MathOverflow)?;
-
-        if  inactive_stake_amount > stake_rent_plus_initial {
-            ctx.accounts.initialize_stake_account(inactive_stake_amount, stake_space, ctx.bumps.new_activating_reserve_stake)?;
-        }
+    let minimum_delegation = std::cmp::max(
+        stake::tools::get_minimum_delegation()?,
+        LAMPORTS_PER_SOL,
+    );
+    let stake_space = std::mem::size_of::<stake::state::StakeStateV2>();
+    let stake_rent_plus_initial = ctx.accounts.rent
+        .minimum_balance(stake_space)
+        .saturating_add(minimum_delegation);
+
+    // Only merge once both accounts are fully active, otherwise the stake program rejects the merge
+    let is_activating_stake_active = ctx.accounts.is_old_activating_stake_active() && ctx.accounts.is_reserve_stake_active()?;
+    // Only claim once the stake is fully cooled down, otherwise the withdraw fails
+    let is_deactivating_stake_inactive = ctx.accounts.is_old_deactivating_stake_inactive();
+    // The rent of the deactivated stake account was fronted by a cranker, it isn't claimed for the reserve
+    let (deactivated_stake_rent, deactivated_stake_amount) = ctx.accounts.get_deactivated_stake_amounts();
+
+    let vote_state = get_vote_state(&ctx.accounts.vote_account.to_account_info())?;
+    let config = ctx.accounts.lending_market.config.clone();
+    let params = EpochRefreshParams {
+        current_epoch: ctx.accounts.clock.epoch,
+        vote_state: &vote_state,
+        config: &config,
+        stake_lamports: ctx.accounts.reserve_stake.lamports(),
+        delegated_stake: get_stake_amount(&ctx.accounts.reserve_stake.to_account_info())?,
+        inactive_stake_amount: ctx.accounts.get_inactive_stake_amount()?,
+        minimum_stake_amount: stake_rent_plus_initial,
+        is_activating_stake_active,
+        is_deactivating_stake_inactive,
+        deactivated_stake_amount,
+    };
+
+    // Update the Epoch, catching up on every epoch elapsed since the last refresh
+    let actions = match ctx.accounts.reserve.refresh_epoch(params)? {
+        Some(actions) => actions,
+        None => return Ok(()),
+    };
+
+    if actions.merge_activating_stake {
+        ctx.accounts.merge_activating_stake_account()?;
+    }
 
-        if let Ok(_) = try_from_slice_unchecked::<StakeStateV2>(&ctx.accounts.old_activating_reserve_stake.data.borrow()) {
-            ctx.accounts.merge_activating_stake_account()?;
-        }
+    if actions.claim_deactivated_stake {
+        ctx.accounts.claim_deactivated_stake_amount(deactivated_stake_rent, deactivated_stake_amount)?;
+    }
 
-        if let Ok(_) = try_from_slice_unchecked::<StakeStateV2>(&ctx.accounts.old_deactivating_reserve_stake.data.borrow()) {
-            ctx.accounts.claim_deactivated_stake_amount()?;
-        }
+    if actions.activate_amount > 0 {
+        ctx.accounts.initialize_stake_account(actions.activate_amount, stake_space, ctx.bumps.new_activating_reserve_stake)?;
+    }
 
-        if reserve.collateral.collateral_amount_to_claim > 0 {
-            ctx.accounts.split_and_deactivate_amount(reserve.collateral.collateral_amount_to_claim)?;
-            reserve.collateral.collateral_amount_to_claim = 0;
-        }
+    if actions.deactivate_amount > 0 {
+        ctx.accounts.split_and_deactivate_amount(actions.deactivate_amount, stake_space, ctx.bumps.new_deactivating_reserve_stake)?;
     }
+
+    // Snapshot the reserve stake so the next refresh only measures rewards
+    let stake_lamports = ctx.accounts.reserve_stake.lamports();
+    let delegated_stake = get_stake_amount(&ctx.accounts.reserve_stake.to_account_info())?;
+    ctx.accounts.reserve.rewards.snapshot(stake_lamports, delegated_stake);
     
     Ok(())
 }
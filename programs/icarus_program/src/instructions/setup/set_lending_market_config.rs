@@ -0,0 +1,32 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{error::LendingError, state::{Versioned, LendingMarket, LendingMarketConfig}, LENDING_MARKET_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct SetLendingMarketConfigArgs {
+    pub config: LendingMarketConfig,
+}
+
+#[derive(Accounts)]
+pub struct SetLendingMarketConfig<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        has_one = owner,
+        seeds = [LENDING_MARKET_PREFIX],
+        bump = lending_market.bump,
+        constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub lending_market: Account<'info, LendingMarket>,
+}
+
+pub fn handler(ctx: Context<SetLendingMarketConfig>, args: SetLendingMarketConfigArgs) -> Result<()> {
+    args.config.validate()?;
+
+    // Update Lending Market Config
+    ctx.accounts.lending_market.config = args.config;
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 pub use {
     anchor_lang::prelude::*,
-    crate::{state::LendingMarket, LENDING_MARKET_PREFIX},
+    crate::{error::LendingError, state::{LendingMarket, Versioned}, LENDING_MARKET_PREFIX},
 };
 
 #[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
@@ -17,6 +17,7 @@ pub struct SetLendingMarketOwner<'info> {
         has_one = owner,
         seeds = [LENDING_MARKET_PREFIX],
         bump = lending_market.bump,
+        constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated,
     )]
     pub lending_market: Account<'info, LendingMarket>,
 }
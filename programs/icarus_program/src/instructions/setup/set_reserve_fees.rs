@@ -0,0 +1,38 @@
+pub use {
+    anchor_lang::prelude::*,
+    crate::{error::LendingError, state::{Versioned, LendingMarket, Reserve, ReserveFees}, LENDING_MARKET_PREFIX, RESERVE_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct SetReserveFeesArgs {
+    pub fees: ReserveFees,
+}
+
+#[derive(Accounts)]
+pub struct SetReserveFees<'info> {
+    pub owner: Signer<'info>,
+    #[account(
+        has_one = owner,
+        seeds = [LENDING_MARKET_PREFIX],
+        bump = lending_market.bump,
+        constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        mut,
+        has_one = lending_market,
+        seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
+        bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub reserve: Account<'info, Reserve>,
+}
+
+pub fn handler(ctx: Context<SetReserveFees>, args: SetReserveFeesArgs) -> Result<()> {
+    args.fees.validate()?;
+
+    // Update Reserve Fees
+    ctx.accounts.reserve.fees = args.fees;
+
+    Ok(())
+}
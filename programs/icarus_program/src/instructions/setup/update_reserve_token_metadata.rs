@@ -0,0 +1,112 @@
+pub use {
+    anchor_lang::prelude::*,
+    solana_program::{system_instruction, program::invoke},
+    anchor_spl::token_interface::{Token2022, token_metadata_update_field, TokenMetadataUpdateField, spl_token_metadata_interface::state::Field},
+    crate::{state::{Versioned, LendingMarket, Reserve, ReserveMint}, error::LendingError, LENDING_MARKET_AUTHORITY_PREFIX, RESERVE_PREFIX},
+};
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenMetadataField {
+    Name,
+    Symbol,
+    Uri,
+}
+
+impl From<TokenMetadataField> for Field {
+    fn from(field: TokenMetadataField) -> Self {
+        match field {
+            TokenMetadataField::Name => Field::Name,
+            TokenMetadataField::Symbol => Field::Symbol,
+            TokenMetadataField::Uri => Field::Uri,
+        }
+    }
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Debug, PartialEq)]
+pub struct UpdateReserveTokenMetadataArgs {
+    pub mint: ReserveMint,
+    pub field: TokenMetadataField,
+    pub value: String,
+}
+
+#[derive(Accounts)]
+pub struct UpdateReserveTokenMetadata<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        has_one = owner @ LendingError::OwnerMismatch,
+        constraint = lending_market.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub lending_market: Account<'info, LendingMarket>,
+    #[account(
+        has_one = lending_market,
+        seeds = [RESERVE_PREFIX, lending_market.key().as_ref(), reserve.vote_account.as_ref()],
+        bump = reserve.bump,
+        constraint = reserve.is_current_version() @ LendingError::AccountNotMigrated,
+    )]
+    pub reserve: Account<'info, Reserve>,
+    #[account(
+        seeds = [LENDING_MARKET_AUTHORITY_PREFIX, lending_market.key().as_ref()],
+        bump = lending_market.authority_bump,
+    )]
+    /// CHECK: PDA account, no need to validate
+    pub lending_market_authority: UncheckedAccount<'info>,
+    #[account(mut, owner = token_program.key() @ LendingError::InvalidMint)]
+    /// CHECK: checked against the Reserve in the handler
+    pub mint: UncheckedAccount<'info>,
+    pub rent: Sysvar<'info, Rent>,
+    pub token_program: Program<'info, Token2022>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> UpdateReserveTokenMetadata<'info> {
+    pub fn update_field(&self, field: Field, value: String) -> Result<()> {
+        let lending_market_key = self.lending_market.key();
+        let authority_seeds = &[LENDING_MARKET_AUTHORITY_PREFIX, lending_market_key.as_ref(), &[self.lending_market.authority_bump]];
+        let signers = &[&authority_seeds[..]];
+
+        token_metadata_update_field(
+            CpiContext::new_with_signer(
+                self.token_program.to_account_info(),
+                TokenMetadataUpdateField {
+                    token_program_id: self.token_program.to_account_info(),
+                    metadata: self.mint.to_account_info(),
+                    update_authority: self.lending_market_authority.to_account_info(),
+                },
+                signers,
+            ),
+            field,
+            value,
+        )?;
+
+        Ok(())
+    }
+
+    /// Keep the mint rent exempt after Token-2022 reallocated the metadata
+    pub fn top_up_rent(&self) -> Result<()> {
+        let minimum_balance = self.rent.minimum_balance(self.mint.data_len());
+        let missing_lamports = minimum_balance.saturating_sub(self.mint.lamports());
+
+        if missing_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(self.owner.key, self.mint.key, missing_lamports),
+                &[
+                    self.owner.to_account_info(),
+                    self.mint.to_account_info(),
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn handler(ctx: Context<UpdateReserveTokenMetadata>, args: UpdateReserveTokenMetadataArgs) -> Result<()> {
+    require_keys_eq!(ctx.accounts.mint.key(), ctx.accounts.reserve.mint_pubkey(args.mint), LendingError::InvalidMint);
+
+    // Update Token Metadata
+    ctx.accounts.update_field(args.field.into(), args.value)?;
+    ctx.accounts.top_up_rent()?;
+
+    Ok(())
+}
@@ -2,10 +2,19 @@ use {
     crate::error::LendingError, 
     anchor_lang::prelude::*, 
     solana_program::{
-        borsh1::try_from_slice_unchecked, clock::Epoch, native_token::LAMPORTS_PER_SOL, stake::{self, state::{Meta, Stake, StakeStateV2}}
+        borsh1::try_from_slice_unchecked, clock::Epoch, native_token::LAMPORTS_PER_SOL, stake_history::StakeHistory,
+        stake::{self, state::{Meta, Stake, StakeActivationStatus, StakeStateV2}}
     }
 };
 
+/// Epoch from which the stake program warms up and cools down at 9% of the
+/// cluster stake per epoch instead of 25% (`reduce_stake_warmup_cooldown`).
+/// Programs can't read the feature set, and the feature is active on every
+/// cluster we deploy to, so the reduced rate is applied to all epochs. Only
+/// stake warming up or cooling down across the feature epoch is valued
+/// differently than by the runtime
+pub const NEW_WARMUP_COOLDOWN_RATE_EPOCH: Option<Epoch> = Some(0);
+
 /// Calculate weighted amount of token, given outstanding token supply, 
 /// pool active stake, and deposit active stake
 pub fn calculate_deposit_amount(
@@ -78,6 +87,37 @@ pub fn get_stake_amount(stake_account_info: &AccountInfo) -> Result<u64> {
     Ok(get_stake_state(stake_account_info)?.1.delegation.stake)
 }
 
+/// Get the effective, activating and deactivating stake of a delegation at the given epoch
+pub fn get_stake_activation(stake: &Stake, current_epoch: Epoch, stake_history: &StakeHistory) -> StakeActivationStatus {
+    stake.delegation.stake_activating_and_deactivating(current_epoch, stake_history, NEW_WARMUP_COOLDOWN_RATE_EPOCH)
+}
+
+/// Deserialize the effective stake amount from AccountInfo, excluding any 
+/// stake that is still warming up or cooling down
+pub fn get_effective_stake_amount(stake_account_info: &AccountInfo, current_epoch: Epoch, stake_history: &StakeHistory) -> Result<u64> {
+    let (_, stake) = get_stake_state(stake_account_info)?;
+    let StakeActivationStatus { effective, deactivating, .. } = get_stake_activation(&stake, current_epoch, stake_history);
+
+    // Stake deactivating this epoch is still reported as effective
+    effective.checked_sub(deactivating).ok_or(LendingError::MathOverflow.into())
+}
+
+/// Determine if stake is fully active, using the stake history
+pub fn is_stake_active(stake: &Stake, current_epoch: Epoch, stake_history: &StakeHistory) -> bool {
+    let status = get_stake_activation(stake, current_epoch, stake_history);
+    status.effective == stake.delegation.stake
+        && status.activating == 0
+        && status.deactivating == 0
+}
+
+/// Determine if stake is fully deactivated, using the stake history
+pub fn is_stake_inactive(stake: &Stake, current_epoch: Epoch, stake_history: &StakeHistory) -> bool {
+    let status = get_stake_activation(stake, current_epoch, stake_history);
+    status.effective == 0
+        && status.activating == 0
+        && status.deactivating == 0
+}
+
 /// Determine if stake is active
 pub fn is_stake_active_without_history(stake: &Stake, current_epoch: Epoch) -> bool {
     stake.delegation.activation_epoch < current_epoch
@@ -92,4 +132,98 @@ pub fn minimum_delegation() -> Result<u64> {
         stake::tools::get_minimum_delegation()?,
         LAMPORTS_PER_SOL,
     ))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        solana_program::{
+            stake_history::StakeHistoryEntry,
+            stake::{state::Delegation, stake_flags::StakeFlags},
+        },
+    };
+
+    const STAKE: u64 = 1_000;
+
+    /// Delegation of `STAKE` activated at epoch 10, deactivated at `deactivation_epoch`
+    fn stake(deactivation_epoch: Epoch) -> Stake {
+        Stake {
+            delegation: Delegation {
+                deactivation_epoch,
+                ..Delegation::new(&Pubkey::new_unique(), STAKE, 10)
+            },
+            credits_observed: 0,
+        }
+    }
+
+    /// Cluster history where the delegation is the only stake warming up in epoch 10 and cooling
+    /// down in epoch 20, the cluster effective stake bounds how much of it changes per epoch
+    fn cluster_history(warmup_cluster_effective: u64, cooldown_cluster_effective: u64) -> StakeHistory {
+        let mut stake_history = StakeHistory::default();
+        stake_history.add(10, StakeHistoryEntry { effective: warmup_cluster_effective, activating: STAKE, deactivating: 0 });
+        stake_history.add(20, StakeHistoryEntry { effective: cooldown_cluster_effective, activating: 0, deactivating: STAKE });
+        stake_history
+    }
+
+    fn effective_stake_amount(stake: Stake, current_epoch: Epoch, stake_history: &StakeHistory) -> u64 {
+        let mut data = StakeStateV2::Stake(Meta::default(), stake, StakeFlags::empty()).try_to_vec().unwrap();
+        let (key, owner, mut lamports) = (Pubkey::new_unique(), stake::program::ID, 0);
+        let account_info = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+        get_effective_stake_amount(&account_info, current_epoch, stake_history).unwrap()
+    }
+
+    #[test]
+    fn test_activating_stake_is_neither_active_nor_inactive() {
+        let stake = stake(Epoch::MAX);
+
+        // Nothing is effective in the activation epoch
+        let stake_history = cluster_history(1_000_000, 1_000_000);
+        assert!(!is_stake_active(&stake, 10, &stake_history));
+        assert!(!is_stake_inactive(&stake, 10, &stake_history));
+        assert_eq!(effective_stake_amount(stake, 10, &stake_history), 0);
+
+        // 9% of a 10_000 cluster warms up per epoch, so only part of the stake is effective
+        let stake_history = cluster_history(10_000, 1_000_000);
+        assert!(!is_stake_active(&stake, 11, &stake_history));
+        assert!(!is_stake_inactive(&stake, 11, &stake_history));
+        assert_eq!(effective_stake_amount(stake, 11, &stake_history), 900);
+    }
+
+    #[test]
+    fn test_active_stake() {
+        let stake = stake(Epoch::MAX);
+        let stake_history = cluster_history(1_000_000, 1_000_000);
+
+        assert!(is_stake_active(&stake, 11, &stake_history));
+        assert!(!is_stake_inactive(&stake, 11, &stake_history));
+        assert_eq!(effective_stake_amount(stake, 11, &stake_history), STAKE);
+    }
+
+    #[test]
+    fn test_deactivating_stake_is_not_effective() {
+        let stake = stake(20);
+
+        // Stake deactivating this epoch is reported effective by the stake program but isn't counted
+        let stake_history = cluster_history(1_000_000, 1_000_000);
+        assert!(!is_stake_active(&stake, 20, &stake_history));
+        assert!(!is_stake_inactive(&stake, 20, &stake_history));
+        assert_eq!(effective_stake_amount(stake, 20, &stake_history), 0);
+
+        // Only part of it cools down in the next epoch
+        let stake_history = cluster_history(1_000_000, 10_000);
+        assert!(!is_stake_active(&stake, 21, &stake_history));
+        assert!(!is_stake_inactive(&stake, 21, &stake_history));
+        assert_eq!(effective_stake_amount(stake, 21, &stake_history), 0);
+    }
+
+    #[test]
+    fn test_inactive_stake() {
+        let stake = stake(20);
+        let stake_history = cluster_history(1_000_000, 1_000_000);
+
+        assert!(!is_stake_active(&stake, 21, &stake_history));
+        assert!(is_stake_inactive(&stake, 21, &stake_history));
+        assert_eq!(effective_stake_amount(stake, 21, &stake_history), 0);
+    }
+}
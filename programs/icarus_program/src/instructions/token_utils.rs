@@ -0,0 +1,159 @@
+use {
+    crate::error::LendingError,
+    anchor_lang::prelude::*,
+    anchor_spl::{
+        associated_token::get_associated_token_address_with_program_id,
+        token_interface::{TokenAccount, mint_to, MintTo, burn, Burn, close_account, CloseAccount},
+    },
+};
+
+/// Collateral Token account of an obligation for a reserve. The receipt tokens of a position
+/// are held by the associated token account of the obligation, so they can't be transferred
+/// and only the program can burn them, whoever settles the position. Positions opened before
+/// the tokens were minted don't have one and are only tracked by the recorded supply
+pub struct ObligationCollateralToken<'a, 'info> {
+    pub token_account: &'a AccountInfo<'info>,
+    pub obligation: &'a AccountInfo<'info>,
+    /// Signer seeds of the obligation, the authority of the token account
+    pub obligation_seeds: &'a [&'a [u8]],
+    pub collateral_mint: &'a AccountInfo<'info>,
+    pub token_program: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info> ObligationCollateralToken<'a, 'info> {
+    /// Collateral Tokens held by the obligation, none if the account doesn't exist
+    pub fn amount(&self) -> Result<Option<u64>> {
+        let address = get_associated_token_address_with_program_id(self.obligation.key, self.collateral_mint.key, self.token_program.key);
+        require_keys_eq!(self.token_account.key(), address, LendingError::InvalidCollateralToken);
+
+        if self.token_account.data_is_empty() {
+            return Ok(None);
+        }
+
+        require_keys_eq!(*self.token_account.owner, self.token_program.key(), LendingError::InvalidCollateralToken);
+        let token_account = TokenAccount::try_deserialize(&mut &self.token_account.data.borrow()[..])?;
+
+        Ok(Some(token_account.amount))
+    }
+
+    /// Mint Collateral Tokens to the obligation, if it holds an account
+    pub fn mint(&self, lending_market_authority: &AccountInfo<'info>, authority_seeds: &[&[u8]], amount: u64) -> Result<()> {
+        if amount == 0 || self.amount()?.is_none() {
+            return Ok(());
+        }
+
+        mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                MintTo {
+                    mint: self.collateral_mint.clone(),
+                    to: self.token_account.clone(),
+                    authority: lending_market_authority.clone(),
+                },
+                &[authority_seeds]
+            ),
+            amount
+        )?;
+
+        Ok(())
+    }
+
+    /// Burn up to `amount` Collateral Tokens held by the obligation
+    pub fn burn(&self, amount: u64) -> Result<()> {
+        let amount = amount.min(self.amount()?.unwrap_or(0));
+        if amount == 0 {
+            return Ok(());
+        }
+
+        burn(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                Burn {
+                    mint: self.collateral_mint.clone(),
+                    from: self.token_account.clone(),
+                    authority: self.obligation.clone(),
+                },
+                &[self.obligation_seeds]
+            ),
+            amount
+        )?;
+
+        Ok(())
+    }
+
+    /// Burn every Collateral Token held by the obligation and close the account, its rent goes to the receiver
+    pub fn close(&self, receiver: &AccountInfo<'info>) -> Result<()> {
+        let Some(amount) = self.amount()? else {
+            return Ok(());
+        };
+        self.burn(amount)?;
+
+        close_account(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                CloseAccount {
+                    account: self.token_account.clone(),
+                    destination: receiver.clone(),
+                    authority: self.obligation.clone(),
+                },
+                &[self.obligation_seeds]
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        anchor_spl::token_interface::spl_token_2022::{self, state::{Account, AccountState}},
+        solana_program::program_pack::Pack,
+    };
+
+    /// Packed token account of `mint` owned by `owner`
+    fn token_account_data(mint: Pubkey, owner: Pubkey, amount: u64) -> Vec<u8> {
+        let mut data = vec![0; Account::LEN];
+        Account { mint, owner, amount, state: AccountState::Initialized, ..Account::default() }.pack_into_slice(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_collateral_tokens_are_read_from_the_obligation_account_only() {
+        let (obligation_key, mint_key, delegate) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let token_program_key = spl_token_2022::ID;
+        let (mut obligation_lamports, mut mint_lamports, mut program_lamports, mut token_lamports) = (0, 0, 0, 0);
+        let (mut obligation_data, mut mint_data, mut program_data) = (vec![], vec![], vec![]);
+        let obligation = AccountInfo::new(&obligation_key, false, true, &mut obligation_lamports, &mut obligation_data, &crate::ID, false, 0);
+        let collateral_mint = AccountInfo::new(&mint_key, false, true, &mut mint_lamports, &mut mint_data, &token_program_key, false, 0);
+        let token_program = AccountInfo::new(&token_program_key, false, false, &mut program_lamports, &mut program_data, &token_program_key, true, 0);
+
+        // The tokens minted for a position are held by the obligation, whoever signs the repay
+        let obligation_token_key = get_associated_token_address_with_program_id(&obligation_key, &mint_key, &token_program_key);
+        let mut token_data = token_account_data(mint_key, obligation_key, 1_000);
+        let token_account = AccountInfo::new(&obligation_token_key, false, true, &mut token_lamports, &mut token_data, &token_program_key, false, 0);
+        let collateral_token = ObligationCollateralToken {
+            token_account: &token_account,
+            obligation: &obligation,
+            obligation_seeds: &[],
+            collateral_mint: &collateral_mint,
+            token_program: &token_program,
+        };
+        assert_eq!(collateral_token.amount().unwrap(), Some(1_000));
+
+        // A delegate can't settle the position with tokens of its own
+        let delegate_token_key = get_associated_token_address_with_program_id(&delegate, &mint_key, &token_program_key);
+        let (mut delegate_lamports, mut delegate_data) = (0, token_account_data(mint_key, delegate, 1_000));
+        let delegate_token = AccountInfo::new(&delegate_token_key, false, true, &mut delegate_lamports, &mut delegate_data, &token_program_key, false, 0);
+        let collateral_token = ObligationCollateralToken { token_account: &delegate_token, ..collateral_token };
+        assert!(collateral_token.amount().is_err());
+
+        // Positions opened before the tokens were minted don't have an account
+        let (mut empty_lamports, mut empty_data) = (0, vec![]);
+        let system_program = solana_program::system_program::ID;
+        let empty_token = AccountInfo::new(&obligation_token_key, false, true, &mut empty_lamports, &mut empty_data, &system_program, false, 0);
+        let collateral_token = ObligationCollateralToken { token_account: &empty_token, ..collateral_token };
+        assert_eq!(collateral_token.amount().unwrap(), None);
+    }
+}
@@ -0,0 +1,44 @@
+use {
+    crate::error::LendingError,
+    anchor_lang::prelude::*,
+    solana_program::{
+        clock::Epoch, program_utils::limited_deserialize, vote::{self, state::{VoteState, VoteStateVersions}}
+    }
+};
+
+/// Deserialize the vote state from AccountInfo
+pub fn get_vote_state(vote_account_info: &AccountInfo) -> Result<VoteState> {
+    require_keys_eq!(*vote_account_info.owner, vote::program::ID, LendingError::InvalidVoteAccount);
+
+    let vote_state_versions = limited_deserialize::<VoteStateVersions>(
+        &vote_account_info.data.borrow(),
+        vote_account_info.data_len() as u64,
+    ).map_err(|_| LendingError::InvalidVoteAccount)?;
+
+    Ok(vote_state_versions.convert_to_current())
+}
+
+/// Credits earned by the validator during the given epoch
+pub fn get_epoch_credits(vote_state: &VoteState, epoch: Epoch) -> u64 {
+    vote_state.epoch_credits()
+        .iter()
+        .rev()
+        .find(|(credits_epoch, _, _)| *credits_epoch == epoch)
+        .map(|(_, credits, prev_credits)| credits.saturating_sub(*prev_credits))
+        .unwrap_or(0)
+}
+
+/// Number of completed epochs since the validator last earned credits
+pub fn get_epochs_without_credits(vote_state: &VoteState, current_epoch: Epoch) -> u64 {
+    let last_credited_epoch = vote_state.epoch_credits()
+        .iter()
+        .rev()
+        .filter(|(credits_epoch, _, _)| *credits_epoch < current_epoch)
+        .find(|(_, credits, prev_credits)| credits > prev_credits)
+        .map(|(credits_epoch, _, _)| *credits_epoch);
+
+    match last_credited_epoch {
+        Some(epoch) => current_epoch.saturating_sub(epoch).saturating_sub(1),
+        None => current_epoch,
+    }
+}
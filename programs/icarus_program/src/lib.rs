@@ -17,6 +17,7 @@ pub const COLLATERAL_MINT_PREFIX: &[u8] = b"collateral_mint";
 pub const LIQUIDITY_MINT_PREFIX: &[u8] = b"liquidity_mint";
 
 pub const OBLIGATION_PREFIX: &[u8] = b"obligation";
+pub const POSITION_MINT_PREFIX: &[u8] = b"position_mint";
 
 pub const MINT_DECIMALS: u8 = 9;
 
@@ -38,14 +39,37 @@ pub mod icarus_program {
         instructions::setup::set_lending_market_owner::handler(ctx, args)
     }
 
+    /// Set the configuration of the lending market
+    pub fn set_lending_market_config(ctx: Context<SetLendingMarketConfig>, args: SetLendingMarketConfigArgs) -> Result<()> {
+        instructions::setup::set_lending_market_config::handler(ctx, args)
+    }
+
     /// Initialize a new reserve
     pub fn init_reserve(ctx: Context<InitializeReserve>) -> Result<()> {
         instructions::setup::init_reserve::handler(ctx)
     }
 
+    /// Update the token metadata of a reserve Token-2022 mint
+    pub fn update_reserve_token_metadata(ctx: Context<UpdateReserveTokenMetadata>, args: UpdateReserveTokenMetadataArgs) -> Result<()> {
+        instructions::setup::update_reserve_token_metadata::handler(ctx, args)
+    }
+
+    /// Set the minimum fees charged when the positions of a reserve are settled
+    pub fn set_reserve_fees(ctx: Context<SetReserveFees>, args: SetReserveFeesArgs) -> Result<()> {
+        instructions::setup::set_reserve_fees::handler(ctx, args)
+    }
+
     /// Crankless Setup Instructions - anyone can sign
 
-    /// todo
+    /// Close spent transient stake accounts of a reserve and reclaim their rent
+    pub fn cleanup_transient_stake<'info>(ctx: Context<'_, '_, '_, 'info, CleanupTransientStake<'info>>, args: CleanupTransientStakeArgs) -> Result<()> {
+        instructions::setup::cleanup_transient_stake::handler(ctx, args)
+    }
+
+    /// Upgrade a lending market, reserve or obligation stored with an older layout
+    pub fn migrate_account<'info>(ctx: Context<'_, '_, '_, 'info, MigrateAccount<'info>>) -> Result<()> {
+        instructions::setup::migrate_account::handler(ctx)
+    }
 
     /// Actions Instructions - user always needs to sign
 
@@ -59,4 +83,40 @@ pub mod icarus_program {
         instructions::actions::reedem_reserve_liquidity::handler(ctx, args)
     }
 
+    /// Transfer an obligation to a new owner. It stays derived from its creator, who can't open
+    /// another obligation until this one is closed
+    pub fn transfer_obligation(ctx: Context<TransferObligation>, args: TransferObligationArgs) -> Result<()> {
+        instructions::actions::transfer_obligation::handler(ctx, args)
+    }
+
+    /// Set or revoke the delegate managing an obligation
+    pub fn set_obligation_delegate(ctx: Context<SetObligationDelegate>, args: SetObligationDelegateArgs) -> Result<()> {
+        instructions::actions::set_obligation_delegate::handler(ctx, args)
+    }
+
+    /// Back the obligation borrows with the collateral of all its positions, or of each position alone
+    pub fn set_obligation_cross_collateral<'info>(ctx: Context<'_, '_, '_, 'info, SetObligationCrossCollateral<'info>>, args: SetObligationCrossCollateralArgs) -> Result<()> {
+        instructions::actions::set_obligation_cross_collateral::handler(ctx, args)
+    }
+
+    /// Close an obligation without positions and return its rent to the owner
+    pub fn close_obligation(ctx: Context<CloseObligation>) -> Result<()> {
+        instructions::actions::close_obligation::handler(ctx)
+    }
+
+    /// Borrow SOL from the reserve vault against the current value of an existing position, without moving stake
+    pub fn borrow_position_liquidity<'info>(ctx: Context<'_, '_, '_, 'info, BorrowPositionLiquidity<'info>>, args: BorrowPositionLiquidityArgs) -> Result<()> {
+        instructions::actions::borrow_position_liquidity::handler(ctx, args)
+    }
+
+    /// Settle the elapsed term of a position and restart it on a new loan type
+    pub fn rollover_position<'info>(ctx: Context<'_, '_, '_, 'info, RolloverPosition<'info>>, args: RolloverPositionArgs) -> Result<()> {
+        instructions::actions::rollover_position::handler(ctx, args)
+    }
+
+    /// Mint a single token representing an obligation position, its holder can repay it
+    pub fn tokenize_position(ctx: Context<TokenizePosition>) -> Result<()> {
+        instructions::actions::tokenize_position::handler(ctx)
+    }
+
 }
\ No newline at end of file
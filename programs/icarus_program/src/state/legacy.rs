@@ -0,0 +1,154 @@
+use {
+    super::*,
+    anchor_lang::prelude::*,
+    solana_program::clock::Epoch,
+};
+
+// Account layouts of previous program versions, only used to migrate accounts
+
+/// Lending market state, version 1
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LendingMarketV1 {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub authority_bump: u8,
+}
+
+impl From<LendingMarketV1> for LendingMarket {
+    fn from(legacy: LendingMarketV1) -> Self {
+        Self {
+            version: PROGRAM_VERSION,
+            owner: legacy.owner,
+            bump: legacy.bump,
+            authority_bump: legacy.authority_bump,
+            config: LendingMarketConfig::default(),
+        }
+    }
+}
+
+/// Reserve state, version 1
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReserveV1 {
+    pub version: u8,
+    pub lending_market: Pubkey,
+    pub vote_account: Pubkey,
+    pub last_epoch: Epoch,
+    pub last_update: LastUpdate,
+    pub liquidity: ReserveLiquidityV1,
+    pub collateral: ReserveCollateralV1,
+    pub bump: u8,
+    pub stake_bump: u8,
+    pub vault_bump: u8,
+}
+
+/// Reserve liquidity, version 1
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReserveLiquidityV1 {
+    pub mint_pubkey: Pubkey,
+    pub mint_total_supply: u64,
+    pub vault_pubkey: Pubkey,
+    pub available_amount: u64,
+    pub borrowed_amount: u64,
+    pub cumulative_borrow_rate_wads: u128,
+}
+
+/// Reserve collateral, version 1
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ReserveCollateralV1 {
+    pub mint_pubkey: Pubkey,
+    pub mint_total_supply: u64,
+    pub stake_account: Pubkey,
+    pub collateral_amount: u64,
+    pub collateral_amount_to_claim: u64,
+}
+
+/// Validator and rewards fields didn't exist in version 1, they are filled
+/// from the vote and stake accounts by the migration
+impl From<ReserveV1> for Reserve {
+    fn from(legacy: ReserveV1) -> Self {
+        Self {
+            version: PROGRAM_VERSION,
+            lending_market: legacy.lending_market,
+            vote_account: legacy.vote_account,
+            last_epoch: legacy.last_epoch,
+            last_update: legacy.last_update,
+            liquidity: ReserveLiquidity {
+                mint_pubkey: legacy.liquidity.mint_pubkey,
+                mint_total_supply: legacy.liquidity.mint_total_supply,
+                vault_pubkey: legacy.liquidity.vault_pubkey,
+                available_amount: legacy.liquidity.available_amount,
+                borrowed_amount: legacy.liquidity.borrowed_amount,
+                cumulative_borrow_rate_wads: legacy.liquidity.cumulative_borrow_rate_wads,
+                accumulated_protocol_fees: 0,
+            },
+            collateral: ReserveCollateral {
+                mint_pubkey: legacy.collateral.mint_pubkey,
+                mint_total_supply: legacy.collateral.mint_total_supply,
+                stake_account: legacy.collateral.stake_account,
+                collateral_amount: legacy.collateral.collateral_amount,
+                collateral_amount_to_claim: legacy.collateral.collateral_amount_to_claim,
+                activating_stake_epoch: None,
+                deactivating_stake_epoch: None,
+                borrowed_amount_to_claim: 0,
+                deactivating_borrowed_amount: 0,
+            },
+            rewards: ReserveRewards::default(),
+            bump: legacy.bump,
+            stake_bump: legacy.stake_bump,
+            vault_bump: legacy.vault_bump,
+            liquidity_mint_bump: 0,
+            collateral_mint_bump: 0,
+            fees: ReserveFees::default(),
+            validator: ReserveValidator::default(),
+        }
+    }
+}
+
+/// Obligation state, version 1
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ObligationV1 {
+    pub version: u8,
+    pub lending_market: Pubkey,
+    pub owner: Pubkey,
+    pub positions: Vec<ObligationPositionV1>,
+    pub bump: u8,
+}
+
+/// Obligation position, version 1
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ObligationPositionV1 {
+    pub vote_account: Pubkey,
+    pub loan_type: LoanType,
+    pub deposited_amount: u64,
+    pub weighted_deposited_amount: u64,
+    pub borrowed_amount: u64,
+}
+
+impl From<ObligationV1> for Obligation {
+    fn from(legacy: ObligationV1) -> Self {
+        Self {
+            version: PROGRAM_VERSION,
+            lending_market: legacy.lending_market,
+            owner: legacy.owner,
+            creator: legacy.owner,
+            delegate: None,
+            cross_collateral: false,
+            positions: legacy.positions
+                .into_iter()
+                .map(|position| ObligationPosition {
+                    vote_account: position.vote_account,
+                    loan_type: position.loan_type,
+                    deposited_amount: position.deposited_amount,
+                    weighted_deposited_amount: position.weighted_deposited_amount,
+                    borrowed_amount: position.borrowed_amount,
+                    pending_deposited_amount: 0,
+                    pending_weighted_deposited_amount: 0,
+                    pending_epoch: 0,
+                    position_mint: None,
+                })
+                .collect(),
+            bump: legacy.bump,
+        }
+    }
+}
@@ -1,8 +1,29 @@
 use {
     super::*,
     anchor_lang::prelude::*,
+    solana_program::vote::state::VoteState,
+    crate::{get_epoch_credits, error::LendingError},
 };
 
+/// Default maximum commission, in %, for a validator to be eligible for a reserve
+pub const DEFAULT_MAX_COMMISSION: u8 = 10;
+/// Default minimum credits earned in the last epoch for a validator to be eligible for a reserve
+pub const DEFAULT_MIN_EPOCH_CREDITS: u64 = 0;
+/// Default number of slots without voting after which a validator is considered delinquent
+pub const DEFAULT_MAX_DELINQUENT_SLOTS: u64 = 128;
+/// Default number of epochs without credits after which a reserve enters reduced-risk mode
+pub const DEFAULT_MAX_EPOCHS_WITHOUT_CREDITS: u64 = 2;
+/// Default percentage of the max LTV still allowed while a reserve is in reduced-risk mode
+pub const DEFAULT_REDUCED_RISK_LTV_PERCENT: u8 = 50;
+/// Default commission increase, in %, between two epoch refreshes that is treated as a commission rug
+pub const DEFAULT_MAX_COMMISSION_INCREASE: u8 = 10;
+/// Default share, in %, of the claimed stake kept as protocol fees
+pub const DEFAULT_PROTOCOL_FEE_PERCENT: u8 = 10;
+/// Default maximum number of positions an obligation can hold
+pub const DEFAULT_MAX_OBLIGATION_POSITIONS: u8 = 10;
+/// Default late fee, in bps of the deposit, charged per epoch after the loan duration
+pub const DEFAULT_LATE_FEE_BPS: u16 = 100;
+
 /// Lending market state
 #[account]
 #[derive(Default)]
@@ -15,6 +36,8 @@ pub struct LendingMarket {
     pub bump: u8,
     /// Bump seed for derived authority address
     pub authority_bump: u8,
+    /// Market configuration
+    pub config: LendingMarketConfig,
 }
 
 impl LendingMarket {
@@ -32,6 +55,15 @@ impl LendingMarket {
         self.owner = params.owner;
         self.bump = params.bump;
         self.authority_bump = params.authority_bump;
+        self.config = LendingMarketConfig::default();
+    }
+
+    /// Deserialize an account stored with the layout of a previous version
+    pub fn migrate(version: u8, data: &[u8]) -> Result<Self> {
+        match version {
+            1 => Ok(LendingMarketV1::deserialize(&mut &data[..])?.into()),
+            _ => Err(LendingError::UnsupportedAccountVersion.into()),
+        }
     }
 
     pub fn is_initialized(&self) -> bool {
@@ -39,9 +71,192 @@ impl LendingMarket {
     }
 }
 
+impl Versioned for LendingMarket {
+    fn version(&self) -> u8 {
+        self.version
+    }
+}
+
 // Space for the account with 128 bytes of padding
 impl Space for LendingMarket {
-    const INIT_SPACE: usize = 8 + 1 + 32 + 1 + 1 + 1 + 128;
+    const INIT_SPACE: usize = 8 + 1 + 32 + 1 + 1 + 1 + LendingMarketConfig::INIT_SPACE + 128;
+}
+
+/// Lending market configuration
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, InitSpace)]
+pub struct LendingMarketConfig {
+    /// Maximum commission, in %, a validator can charge to be eligible for a reserve
+    pub max_commission: u8,
+    /// Minimum credits a validator must have earned in the last completed epoch
+    pub min_epoch_credits: u64,
+    /// Maximum slots since the last vote before a validator is considered delinquent
+    pub max_delinquent_slots: u64,
+    /// Epochs without credits after which a reserve enters reduced-risk mode
+    pub max_epochs_without_credits: u64,
+    /// Percentage of the max LTV still allowed while a reserve is in reduced-risk mode
+    pub reduced_risk_ltv_percent: u8,
+    /// Commission increase, in %, between two epoch refreshes that is treated as a commission rug
+    pub max_commission_increase: u8,
+    /// Waive the repayment fees of loans that were open during a commission rug
+    pub commission_rug_protection: bool,
+    /// Share, in %, of the claimed stake kept as protocol fees, the rest goes to liquidity providers
+    pub protocol_fee_percent: u8,
+    /// Maximum number of positions an obligation can hold
+    pub max_obligation_positions: u8,
+    /// Late fees of short term loans
+    pub short_late_fee: LateFeeSchedule,
+    /// Late fees of medium term loans
+    pub medium_late_fee: LateFeeSchedule,
+    /// Late fees of long term loans
+    pub long_late_fee: LateFeeSchedule,
+}
+
+impl Default for LendingMarketConfig {
+    fn default() -> Self {
+        Self {
+            max_commission: DEFAULT_MAX_COMMISSION,
+            min_epoch_credits: DEFAULT_MIN_EPOCH_CREDITS,
+            max_delinquent_slots: DEFAULT_MAX_DELINQUENT_SLOTS,
+            max_epochs_without_credits: DEFAULT_MAX_EPOCHS_WITHOUT_CREDITS,
+            reduced_risk_ltv_percent: DEFAULT_REDUCED_RISK_LTV_PERCENT,
+            max_commission_increase: DEFAULT_MAX_COMMISSION_INCREASE,
+            commission_rug_protection: true,
+            protocol_fee_percent: DEFAULT_PROTOCOL_FEE_PERCENT,
+            max_obligation_positions: DEFAULT_MAX_OBLIGATION_POSITIONS,
+            short_late_fee: LateFeeSchedule::default(),
+            medium_late_fee: LateFeeSchedule::default(),
+            long_late_fee: LateFeeSchedule::default(),
+        }
+    }
+}
+
+impl LendingMarketConfig {
+    /// Validate the market config, when initializing or modifying the market
+    pub fn validate(&self) -> Result<()> {
+        if self.max_commission > 100 {
+            msg!("Max commission must be in range [0, 100]");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        if self.reduced_risk_ltv_percent > 100 {
+            msg!("Reduced-risk LTV percent must be in range [0, 100]");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        if self.max_commission_increase > 100 {
+            msg!("Max commission increase must be in range [0, 100]");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        if self.protocol_fee_percent > 100 {
+            msg!("Protocol fee percent must be in range [0, 100]");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        if self.max_obligation_positions == 0 {
+            msg!("Max obligation positions must be at least 1");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        self.short_late_fee.validate()?;
+        self.medium_late_fee.validate()?;
+        self.long_late_fee.validate()?;
+        Ok(())
+    }
+
+    /// Late fee schedule of the loan type
+    pub fn late_fee_schedule(&self, loan_type: &LoanType) -> &LateFeeSchedule {
+        match loan_type {
+            LoanType::SHORT(_) => &self.short_late_fee,
+            LoanType::MEDIUM(_) => &self.medium_late_fee,
+            LoanType::LONG(_) => &self.long_late_fee,
+        }
+    }
+
+    /// Check that the validator behind the vote state can back a reserve
+    pub fn check_validator_eligibility(&self, vote_state: &VoteState, clock: &Clock) -> Result<()> {
+        require_gte!(self.max_commission, vote_state.commission, LendingError::ValidatorCommissionTooHigh);
+
+        let last_epoch_credits = get_epoch_credits(vote_state, clock.epoch.saturating_sub(1));
+        require_gte!(last_epoch_credits, self.min_epoch_credits, LendingError::ValidatorInsufficientCredits);
+
+        let last_voted_slot = vote_state.last_voted_slot().ok_or(LendingError::ValidatorDelinquent)?;
+        require_gte!(
+            last_voted_slot.saturating_add(self.max_delinquent_slots), 
+            clock.slot, 
+            LendingError::ValidatorDelinquent
+        );
+
+        Ok(())
+    }
+}
+
+/// How the late fee grows with the epochs past the grace period
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum LateFeeCurve {
+    /// `rate_bps` charged every late epoch
+    Linear,
+    /// `rate_bps` charged at the start of every `step_epochs` late epochs
+    Stepwise { step_epochs: u64 },
+    /// `rate_bps` charged on the first late epoch, doubling every epoch after
+    Exponential,
+}
+
+/// Late fee charged on the deposit of a loan repaid after its duration
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct LateFeeSchedule {
+    /// Epochs after the loan duration without late fees
+    pub grace_epochs: u64,
+    /// Shape of the late fee
+    pub curve: LateFeeCurve,
+    /// Late fee rate, in bps of the deposit
+    pub rate_bps: u16,
+    /// Maximum late fee, in bps of the deposit
+    pub cap_bps: u16,
+}
+
+impl Default for LateFeeSchedule {
+    fn default() -> Self {
+        Self {
+            grace_epochs: 0,
+            curve: LateFeeCurve::Linear,
+            rate_bps: DEFAULT_LATE_FEE_BPS,
+            cap_bps: MAX_BASIS_POINTS as u16,
+        }
+    }
+}
+
+impl LateFeeSchedule {
+    /// Validate the schedule, when initializing or modifying the market
+    pub fn validate(&self) -> Result<()> {
+        if self.cap_bps as u64 > MAX_BASIS_POINTS {
+            msg!("Late fee cap must be in range [0, 10000] bps");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        if let LateFeeCurve::Stepwise { step_epochs: 0 } = self.curve {
+            msg!("Late fee steps must last at least 1 epoch");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        Ok(())
+    }
+
+    /// Late fee, in bps of the deposit, after the given number of epochs past the loan duration
+    pub fn fee_bps(&self, epochs_after_duration: u64) -> u64 {
+        let late_epochs = epochs_after_duration.saturating_sub(self.grace_epochs);
+        if late_epochs == 0 {
+            return 0;
+        }
+
+        let rate_bps = self.rate_bps as u64;
+        let fee_bps = match self.curve {
+            LateFeeCurve::Linear => rate_bps.saturating_mul(late_epochs),
+            LateFeeCurve::Stepwise { step_epochs } => {
+                let steps = late_epochs.div_ceil(step_epochs.max(1));
+                rate_bps.saturating_mul(steps)
+            },
+            LateFeeCurve::Exponential => {
+                let multiplier = 1u64.checked_shl((late_epochs - 1).min(63) as u32).unwrap_or(u64::MAX);
+                rate_bps.saturating_mul(multiplier)
+            },
+        };
+
+        fee_bps.min(self.cap_bps as u64)
+    }
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
@@ -53,4 +268,133 @@ pub struct InitLendingMarketParams {
     pub bump: u8,
     /// Bump seed for derived authority address
     pub authority_bump: u8,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        solana_program::{clock::{Epoch, Slot}, vote::state::{LandedVote, Lockout}},
+    };
+
+    /// Vote state with the given commission, credits earned in `credits_epoch` and last vote at `last_voted_slot`
+    fn vote_state(commission: u8, credits_epoch: Epoch, credits: u64, last_voted_slot: Option<Slot>) -> VoteState {
+        let mut vote_state = VoteState::default();
+        vote_state.commission = commission;
+        vote_state.increment_credits(credits_epoch, credits);
+        if let Some(slot) = last_voted_slot {
+            vote_state.votes.push_back(LandedVote::from(Lockout::new(slot)));
+        }
+        vote_state
+    }
+
+    fn clock(epoch: Epoch, slot: Slot) -> Clock {
+        Clock { epoch, slot, ..Clock::default() }
+    }
+
+    fn schedule(grace_epochs: u64, curve: LateFeeCurve, rate_bps: u16, cap_bps: u16) -> LateFeeSchedule {
+        LateFeeSchedule { grace_epochs, curve, rate_bps, cap_bps }
+    }
+
+    #[test]
+    fn test_default_late_fee_is_one_percent_per_epoch() {
+        let schedule = LateFeeSchedule::default();
+        assert_eq!(schedule.fee_bps(0), 0);
+        assert_eq!(schedule.fee_bps(1), 100);
+        assert_eq!(schedule.fee_bps(99), 9_900);
+        assert_eq!(schedule.fee_bps(100), 10_000);
+        assert_eq!(schedule.fee_bps(101), 10_000);
+    }
+
+    #[test]
+    fn test_late_fee_grace_period_boundaries() {
+        let schedule = schedule(3, LateFeeCurve::Linear, 50, 10_000);
+        assert_eq!(schedule.fee_bps(0), 0);
+        assert_eq!(schedule.fee_bps(2), 0);
+        assert_eq!(schedule.fee_bps(3), 0);
+        assert_eq!(schedule.fee_bps(4), 50);
+        assert_eq!(schedule.fee_bps(5), 100);
+    }
+
+    #[test]
+    fn test_linear_late_fee_cap_boundaries() {
+        let schedule = schedule(2, LateFeeCurve::Linear, 100, 500);
+        assert_eq!(schedule.fee_bps(6), 400);
+        assert_eq!(schedule.fee_bps(7), 500);
+        assert_eq!(schedule.fee_bps(8), 500);
+        assert_eq!(schedule.fee_bps(u64::MAX), 500);
+    }
+
+    #[test]
+    fn test_stepwise_late_fee_step_boundaries() {
+        let schedule = schedule(1, LateFeeCurve::Stepwise { step_epochs: 3 }, 200, 500);
+        assert_eq!(schedule.fee_bps(1), 0);
+        // First step starts on the first late epoch
+        assert_eq!(schedule.fee_bps(2), 200);
+        assert_eq!(schedule.fee_bps(4), 200);
+        assert_eq!(schedule.fee_bps(5), 400);
+        assert_eq!(schedule.fee_bps(7), 400);
+        // Third step is capped
+        assert_eq!(schedule.fee_bps(8), 500);
+        assert_eq!(schedule.fee_bps(u64::MAX), 500);
+    }
+
+    #[test]
+    fn test_exponential_late_fee_boundaries() {
+        let schedule = schedule(2, LateFeeCurve::Exponential, 100, 1_000);
+        assert_eq!(schedule.fee_bps(2), 0);
+        assert_eq!(schedule.fee_bps(3), 100);
+        assert_eq!(schedule.fee_bps(4), 200);
+        assert_eq!(schedule.fee_bps(5), 400);
+        assert_eq!(schedule.fee_bps(6), 800);
+        assert_eq!(schedule.fee_bps(7), 1_000);
+        // Doubling saturates instead of overflowing
+        assert_eq!(schedule.fee_bps(66), 1_000);
+        assert_eq!(schedule.fee_bps(u64::MAX), 1_000);
+    }
+
+    #[test]
+    fn test_zero_late_fee_rate_or_cap() {
+        assert_eq!(schedule(0, LateFeeCurve::Exponential, 0, 10_000).fee_bps(u64::MAX), 0);
+        assert_eq!(schedule(0, LateFeeCurve::Linear, 100, 0).fee_bps(10), 0);
+    }
+
+    #[test]
+    fn test_late_fee_schedule_validation() {
+        assert!(LateFeeSchedule::default().validate().is_ok());
+        assert!(schedule(0, LateFeeCurve::Linear, 100, 10_001).validate().is_err());
+        assert!(schedule(0, LateFeeCurve::Stepwise { step_epochs: 0 }, 100, 10_000).validate().is_err());
+        assert!(schedule(0, LateFeeCurve::Stepwise { step_epochs: 1 }, 100, 10_000).validate().is_ok());
+    }
+
+    #[test]
+    fn test_validator_eligibility_commission() {
+        let config = LendingMarketConfig { min_epoch_credits: 1, ..LendingMarketConfig::default() };
+        let clock = clock(10, 1_000);
+
+        assert!(config.check_validator_eligibility(&vote_state(DEFAULT_MAX_COMMISSION, 9, 1, Some(1_000)), &clock).is_ok());
+        assert!(config.check_validator_eligibility(&vote_state(DEFAULT_MAX_COMMISSION + 1, 9, 1, Some(1_000)), &clock).is_err());
+    }
+
+    #[test]
+    fn test_validator_eligibility_epoch_credits() {
+        let config = LendingMarketConfig { min_epoch_credits: 100, ..LendingMarketConfig::default() };
+        let clock = clock(10, 1_000);
+
+        assert!(config.check_validator_eligibility(&vote_state(0, 9, 100, Some(1_000)), &clock).is_ok());
+        assert!(config.check_validator_eligibility(&vote_state(0, 9, 99, Some(1_000)), &clock).is_err());
+        // Only the credits of the last completed epoch count
+        assert!(config.check_validator_eligibility(&vote_state(0, 8, 100, Some(1_000)), &clock).is_err());
+        assert!(config.check_validator_eligibility(&vote_state(0, 10, 100, Some(1_000)), &clock).is_err());
+    }
+
+    #[test]
+    fn test_validator_eligibility_delinquency() {
+        let config = LendingMarketConfig::default();
+        let clock = clock(10, 1_000);
+
+        assert!(config.check_validator_eligibility(&vote_state(0, 9, 1, Some(1_000 - DEFAULT_MAX_DELINQUENT_SLOTS)), &clock).is_ok());
+        assert!(config.check_validator_eligibility(&vote_state(0, 9, 1, Some(999 - DEFAULT_MAX_DELINQUENT_SLOTS)), &clock).is_err());
+        // A validator that never voted is delinquent
+        assert!(config.check_validator_eligibility(&vote_state(0, 9, 1, None), &clock).is_err());
+    }
+}
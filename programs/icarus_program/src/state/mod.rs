@@ -2,8 +2,9 @@ mod lending_market;
 mod reserve;
 mod obligation;
 mod last_update;
+mod legacy;
 
-pub use {lending_market::*, reserve::*, last_update::*, obligation::*};
+pub use {lending_market::*, reserve::*, last_update::*, obligation::*, legacy::*};
 
 /// Collateral tokens are initially valued at a ratio of 5:1
 /// (collateral:liquidity)
@@ -12,12 +13,23 @@ pub const INITIAL_COLLATERAL_RATIO: u64 = 1;
 const INITIAL_COLLATERAL_RATE: u64 = INITIAL_COLLATERAL_RATIO * WAD;
 
 /// Current version of the program and all new accounts created
-pub const PROGRAM_VERSION: u8 = 1;
+pub const PROGRAM_VERSION: u8 = 2;
 
 /// Accounts are created with data zeroed out, so uninitialized state instances
 /// will have the version set to 0.
 pub const UNINITIALIZED_VERSION: u8 = 0;
 
+/// Accounts carrying the version of their layout
+pub trait Versioned {
+    /// Version of the account layout
+    fn version(&self) -> u8;
+
+    /// Whether the account uses the layout of the current program version
+    fn is_current_version(&self) -> bool {
+        self.version() == PROGRAM_VERSION
+    }
+}
+
 //// Scale of precision
 pub const SCALE: usize = 18;
 /// Identity
@@ -28,23 +40,36 @@ pub const HALF_WAD: u64 = 500_000_000_000_000_000;
 pub const PERCENT_SCALER: u64 = 10_000_000_000_000_000;
 /// Scale for Basis Points
 pub const BASIS_POINT_SCALER: u64 = 100_000_000_000_000;
+/// Basis points in 100%
+pub const MAX_BASIS_POINTS: u64 = 10_000;
 
-/// Minimum Fee for same Epoch Borrowing in BPS
-pub const MINIMUM_FEE: u64 = 100; // move to the pool config
+/// Default Minimum Fee for same Epoch Borrowing in BPS
+pub const MINIMUM_FEE: u64 = 100;
+/// Default Minimum Fee for every repayment in BPS
+pub const MINIMUM_REPAY_FEE: u64 = 10;
 
-/// Fee for Late Repayment in %
-pub const LATE_FEE: u64 = 1; // move to the pool config
 
-/// Max TVL Ratio for Short, Medium and Long Term Loans
-pub const SHORT_MAX_RATIO: u64 = 90;
-pub const MEDIUM_MAX_RATIO: u64 = 80;
-pub const LONG_MAX_RATIO: u64 = 70;
+/// Max LTV Ratio for Short, Medium and Long Term Loans, in bps
+pub const SHORT_MAX_RATIO: u64 = 9_000;
+pub const MEDIUM_MAX_RATIO: u64 = 8_000;
+pub const LONG_MAX_RATIO: u64 = 7_000;
 
 /// Duraction for Short, Medium and Long Term Loans
 pub const SHORT_LOAN_DURATION: u64 = 15;
 pub const MEDIUM_LOAN_DURATION: u64 = 45;
 pub const LONG_LOAN_DURATION: u64 = 90;
 
+/// Epochs of validator commission kept by a reserve, covering a long loan and its late epochs
+pub const COMMISSION_HISTORY_EPOCHS: usize = 256;
+
+/// Direction of rounding when leaving or dividing fixed precision,
+/// always picked against the borrower
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FixedPrecision(pub u128);
 
@@ -61,6 +86,33 @@ impl FixedPrecision {
         FixedPrecision(((percent as u128) * PERCENT_SCALER as u128) / 100)
     }
 
+    pub fn from_bps(bps: u64) -> Self {
+        FixedPrecision((bps as u128) * BASIS_POINT_SCALER as u128)
+    }
+
+    /// Ratio of two amounts
+    pub fn from_ratio(numerator: u128, denominator: u128, rounding: Rounding) -> Result<Self> {
+        if denominator == 0 {
+            return Err(error!(LendingError::DivideByZero));
+        }
+        let scaled = numerator.checked_mul(WAD as u128).ok_or_else(|| error!(LendingError::MathOverflow))?;
+        let ratio = match rounding {
+            Rounding::Down => scaled / denominator,
+            Rounding::Up => scaled.div_ceil(denominator),
+        };
+        Ok(FixedPrecision(ratio))
+    }
+
+    /// Apply the value to an amount
+    pub fn apply(&self, amount: u64, rounding: Rounding) -> Result<u64> {
+        let scaled = (amount as u128).checked_mul(self.0).ok_or_else(|| error!(LendingError::MathOverflow))?;
+        let applied = match rounding {
+            Rounding::Down => scaled / WAD as u128,
+            Rounding::Up => scaled.div_ceil(WAD as u128),
+        };
+        u64::try_from(applied).map_err(|_| error!(LendingError::MathOverflow))
+    }
+
     pub fn checked_add(&self, other: &Self) -> Result<Self> {
         self.0.checked_add(other.0)
             .map(FixedPrecision)
@@ -90,7 +142,59 @@ impl FixedPrecision {
             .ok_or_else(|| error!(LendingError::MathOverflow))
     }
 
+    pub fn checked_div_rounded(&self, other: &Self, rounding: Rounding) -> Result<Self> {
+        Self::from_ratio(self.0, other.0, rounding)
+    }
+
     pub fn round_u64(&self) -> u64 {
         ((self.0 + (HALF_WAD as u128)) / (WAD as u128)) as u64
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_precision_rounding() {
+        // (numerator, denominator, rounded down, rounded up)
+        let cases: &[(u128, u128, u128, u128)] = &[
+            (0, 1, 0, 0),
+            (1, 1, WAD as u128, WAD as u128),
+            (1, 3, WAD as u128 / 3, WAD as u128 / 3 + 1),
+            (2, 3, 2 * WAD as u128 / 3, 2 * WAD as u128 / 3 + 1),
+            (1, WAD as u128, 1, 1),
+            (1, 2 * WAD as u128, 0, 1),
+            (u64::MAX as u128, 1, u64::MAX as u128 * WAD as u128, u64::MAX as u128 * WAD as u128),
+        ];
+
+        for &(numerator, denominator, down, up) in cases {
+            assert_eq!(FixedPrecision::from_ratio(numerator, denominator, Rounding::Down).unwrap(), FixedPrecision(down));
+            assert_eq!(FixedPrecision::from_ratio(numerator, denominator, Rounding::Up).unwrap(), FixedPrecision(up));
+        }
+
+        assert!(FixedPrecision::from_ratio(1, 0, Rounding::Up).is_err());
+        assert!(FixedPrecision::from_ratio(u128::MAX, 1, Rounding::Up).is_err());
+    }
+
+    #[test]
+    fn test_fixed_precision_apply() {
+        // (value in bps, amount, rounded down, rounded up)
+        let cases: &[(u64, u64, u64, u64)] = &[
+            (0, 1_000, 0, 0),
+            (MAX_BASIS_POINTS, 1_000, 1_000, 1_000),
+            (9_000, 1_000, 900, 900),
+            (9_000, 1_001, 900, 901),
+            (1, 9_999, 0, 1),
+            (1, 10_000, 1, 1),
+            (MAX_BASIS_POINTS, u64::MAX, u64::MAX, u64::MAX),
+        ];
+
+        for &(bps, amount, down, up) in cases {
+            assert_eq!(FixedPrecision::from_bps(bps).apply(amount, Rounding::Down).unwrap(), down);
+            assert_eq!(FixedPrecision::from_bps(bps).apply(amount, Rounding::Up).unwrap(), up);
+        }
+
+        assert!(FixedPrecision::from_bps(2 * MAX_BASIS_POINTS).apply(u64::MAX, Rounding::Down).is_err());
+    }
+}
@@ -32,15 +32,27 @@ pub struct Obligation {
     pub lending_market: Pubkey,
     /// Owner of the obligation
     pub owner: Pubkey,
+    /// Original owner of the obligation, used in its PDA seeds
+    pub creator: Pubkey,
+    /// Optional authority managing the obligation on behalf of the owner
+    pub delegate: Option<ObligationDelegate>,
+    /// Whether borrows are backed by the collateral of all positions
+    pub cross_collateral: bool,
     /// Current Active Position
     pub positions: Vec<ObligationPosition>,
     /// Bump seed for Obligation
     pub bump: u8,
 }
 
-// Maxing out the space because it could be custom rule on the resize and don't want to handle that manually.
+impl Versioned for Obligation {
+    fn version(&self) -> u8 {
+        self.version
+    }
+}
+
+// Created without positions, the account is resized as positions are added and removed
 impl Space for Obligation {
-    const INIT_SPACE: usize = 1024; 
+    const INIT_SPACE: usize = 8 + 1 + 32 + 32 + 32 + 1 + ObligationDelegate::INIT_SPACE + 1 + 4 + 1;
 }
 
 impl Obligation {
@@ -56,23 +68,83 @@ impl Obligation {
         self.version = PROGRAM_VERSION;
         self.lending_market = params.lending_market;
         self.owner = params.owner;
+        self.creator = params.owner;
+        self.delegate = None;
+        self.cross_collateral = false;
         self.positions = vec![];
         self.bump = params.bump
     }
 
-    /// Get or create a new position
-    pub fn add_or_create_position(&mut self, params: InitObligationPositionParams) -> Result<()> {
+    /// Deserialize an account stored with the layout of a previous version
+    pub fn migrate(version: u8, data: &[u8]) -> Result<Self> {
+        match version {
+            1 => Ok(ObligationV1::deserialize(&mut &data[..])?.into()),
+            _ => Err(LendingError::UnsupportedAccountVersion.into()),
+        }
+    }
+
+    /// Space needed to hold the given number of positions
+    pub fn space(positions: usize) -> usize {
+        Self::INIT_SPACE + positions * ObligationPosition::INIT_SPACE
+    }
+
+    /// Transfer the obligation to a new owner, the delegate is revoked. The creator stays in
+    /// the PDA seeds, so it can't open another obligation while this one exists
+    pub fn transfer(&mut self, new_owner: Pubkey) {
+        self.owner = new_owner;
+        self.delegate = None;
+    }
+
+    /// Check that the signer is the owner or a delegate allowed to perform the action
+    pub fn check_authority(&self, authority: Pubkey, action: ObligationAction) -> Result<()> {
+        if authority == self.owner {
+            return Ok(());
+        }
+
+        match &self.delegate {
+            Some(delegate) if delegate.authority == authority => {
+                require!(delegate.permissions.allows(action), LendingError::DelegateNotAllowed);
+                Ok(())
+            },
+            _ => Err(LendingError::ObligationAuthorityMismatch.into()),
+        }
+    }
+
+    /// Check that the signer can settle the position and return who receives its collateral.
+    /// A tokenized position follows its token holder, the others are settled by the owner or an
+    /// allowed delegate and always return the collateral to the owner
+    pub fn settlement_recipient(&self, authority: Pubkey, vote_account: Pubkey) -> Result<Pubkey> {
+        let (position, _) = self.find_position(vote_account)?;
+        if position.position_mint.is_some() {
+            return Ok(authority);
+        }
+
+        self.check_authority(authority, ObligationAction::Repay)?;
+        Ok(self.owner)
+    }
+
+    /// Get or create a new position, up to the max number of positions. The deposit of a new
+    /// position is pending until the next epoch refresh, so it is opened without a borrow and
+    /// borrowed against with `borrow_position_liquidity` once the reserve is refreshed
+    pub fn add_or_create_position(&mut self, params: InitObligationPositionParams, max_positions: u8) -> Result<()> {
+        let max_ratio = self.position_max_ratio(params.max_ratio);
         match self.find_index(params.vote_account) {
             Some(index) => {
-                // Position exists, update it
+                // Position exists, update it and keep its loan start. Collateral matured since the
+                // last deposit is settled first so the new deposit doesn't send it back to pending
                 let position = &mut self.positions[index];
-                require!(position.loan_type == params.loan_type, LendingError::LoanTypeMismatch);
-                position.deposit(params.deposited_amount, params.weighted_deposited_amount)?;
-                position.borrow(params.borrowed_amount)?;
+                require!(position.loan_type.is_same_kind(&params.loan_type), LendingError::LoanTypeMismatch);
+                require!(position.position_mint.is_none(), LendingError::PositionTokenized);
+                position.settle_pending(params.refreshed_epoch)?;
+                position.deposit(params.deposited_amount, params.weighted_deposited_amount, params.loan_type.epoch())?;
+                position.borrow(params.borrowed_amount, max_ratio)?;
             },
             None => {
                 // Position doesn't exist, create a new one
+                require_gt!(max_positions as usize, self.positions.len(), LendingError::TooManyPositions);
+                require_eq!(params.borrowed_amount, 0, LendingError::PendingCollateral);
                 let new_position = ObligationPosition::new(params);
+                new_position.check_ltv(new_position.borrowed_amount, new_position.deposited_amount, max_ratio)?;
                 self.positions.push(new_position);
             }
         }
@@ -81,9 +153,9 @@ impl Obligation {
     }
 
     /// Repay Loan
-    pub fn repay_or_liquidate(&mut self, vote_account: Pubkey, current_epoch: Epoch) -> Result<(bool, u64)> {
+    pub fn repay_or_liquidate(&mut self, vote_account: Pubkey, current_epoch: Epoch, config: &LendingMarketConfig) -> Result<(bool, u64)> {
         let index = self.find_index(vote_account).ok_or(LendingError::InvalidObligationPositionIndex)?;
-        let (is_liquidatable, deposited_amount_after_fees) = self.positions[index].get_deposit_status(current_epoch)?;
+        let (is_liquidatable, deposited_amount_after_fees) = self.positions[index].get_deposit_status(current_epoch, config)?;
         self.positions.remove(index);
 
         Ok((is_liquidatable, deposited_amount_after_fees)) 
@@ -119,6 +191,82 @@ impl Obligation {
         Ok((&self.positions[position_index], position_index))
     }
 
+    /// Find mutable position by vote_account
+    pub fn find_position_mut(&mut self, vote_account: Pubkey) -> Result<&mut ObligationPosition> {
+        let position_index = self
+            .find_index(vote_account)
+            .ok_or(LendingError::InvalidObligationPositionIndex)?;
+        Ok(&mut self.positions[position_index])
+    }
+
+    /// Max LTV ratio, in bps, a single position is checked against. In cross collateral
+    /// mode the LTV is checked over all positions, each one only has to stay solvent
+    pub fn position_max_ratio(&self, reserve_max_ratio: u64) -> u64 {
+        if self.cross_collateral { MAX_BASIS_POINTS } else { reserve_max_ratio }
+    }
+
+    /// Switch cross collateral mode, the positions must be healthy in the new mode
+    pub fn set_cross_collateral(&mut self, cross_collateral: bool, collaterals: &[PositionCollateral]) -> Result<()> {
+        self.check_collaterals(collaterals)?;
+        self.cross_collateral = cross_collateral;
+
+        if cross_collateral {
+            return self.check_health(collaterals);
+        }
+        for (position, collateral) in self.positions.iter().zip(collaterals) {
+            position.check_ltv(position.borrowed_amount, position.deposited_amount, collateral.max_ratio)?;
+        }
+
+        Ok(())
+    }
+
+    /// Total borrowed amount across positions
+    pub fn borrowed_amount(&self) -> Result<u64> {
+        self.positions.iter().try_fold(0u64, |total, position| {
+            total.checked_add(position.borrowed_amount).ok_or_else(|| LendingError::MathOverflow.into())
+        })
+    }
+
+    /// Amount the obligation can borrow against all its positions, rounded down
+    pub fn borrowing_capacity(&self, collaterals: &[PositionCollateral]) -> Result<u64> {
+        self.check_collaterals(collaterals)?;
+
+        collaterals.iter().try_fold(0u64, |total, collateral| {
+            let capacity = FixedPrecision::from_bps(collateral.max_ratio).apply(collateral.collateral_value, Rounding::Down)?;
+            total.checked_add(capacity).ok_or_else(|| LendingError::MathOverflow.into())
+        })
+    }
+
+    /// Borrowing capacity over borrowed amount, `None` without borrows
+    pub fn health_factor(&self, collaterals: &[PositionCollateral]) -> Result<Option<FixedPrecision>> {
+        let borrowed_amount = self.borrowed_amount()?;
+        if borrowed_amount == 0 {
+            return Ok(None);
+        }
+
+        let borrowing_capacity = self.borrowing_capacity(collaterals)?;
+        Ok(Some(FixedPrecision::from_ratio(borrowing_capacity as u128, borrowed_amount as u128, Rounding::Down)?))
+    }
+
+    /// Check that the borrows are covered by the collateral of all positions
+    pub fn check_health(&self, collaterals: &[PositionCollateral]) -> Result<()> {
+        if let Some(health_factor) = self.health_factor(collaterals)? {
+            require!(health_factor >= FixedPrecision::from_wad(WAD), LendingError::ObligationUnhealthy);
+        }
+
+        Ok(())
+    }
+
+    /// Check that there is one collateral per position, in the same order
+    fn check_collaterals(&self, collaterals: &[PositionCollateral]) -> Result<()> {
+        require_eq!(collaterals.len(), self.positions.len(), LendingError::InvalidObligationReserves);
+        for (position, collateral) in self.positions.iter().zip(collaterals) {
+            require_keys_eq!(position.vote_account, collateral.vote_account, LendingError::InvalidObligationReserves);
+        }
+
+        Ok(())
+    }
+
     /// Find index by vote_account
     pub fn find_index(&self, vote_account: Pubkey) -> Option<usize> {
         self.positions
@@ -127,6 +275,44 @@ impl Obligation {
     }
 }
 
+/// Observed state of a position when it is settled
+pub struct SettlementQuoteParams {
+    /// Current value of the position collateral
+    pub stake_current_value: u64,
+    /// Deposited amount left after the late fees
+    pub deposited_amount_after_fee: u64,
+    /// Whether the reward share is waived
+    pub is_fee_waived: bool,
+    /// Minimum fee, in bps of the borrowed amount
+    pub minimum_fee_bps: u64,
+}
+
+/// Fees collected when settling a position
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SettlementQuote {
+    /// Share of the rewards earned on the collateral
+    pub reward_share: u64,
+    /// Minimum fee, charged when above the reward share
+    pub minimum_fee: u64,
+    /// Late fees
+    pub late_fee: u64,
+    /// Total fee collected, it goes to the liquidity providers
+    pub fee: u64,
+    /// Collateral returned to the holder of the position
+    pub returned_amount: u64,
+}
+
+/// Collateral of a position, valued by its reserve
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionCollateral {
+    /// Vote Account Address of the position
+    pub vote_account: Pubkey,
+    /// Current value of the deposited collateral, in lamports
+    pub collateral_value: u64,
+    /// Max LTV ratio allowed by the Reserve, in bps
+    pub max_ratio: u64,
+}
+
 /// Initialize an obligation
 pub struct InitObligationParams {
     /// Lending market address
@@ -137,6 +323,58 @@ pub struct InitObligationParams {
     pub bump: u8,
 }
 
+/// Authority managing an obligation on behalf of its owner
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ObligationDelegate {
+    /// Delegate authority
+    pub authority: Pubkey,
+    /// Actions the delegate may perform
+    pub permissions: ObligationPermissions,
+}
+
+impl Space for ObligationDelegate {
+    const INIT_SPACE: usize = 32 + ObligationPermissions::INIT_SPACE;
+}
+
+/// Actions a delegate may perform on an obligation. Tokenized positions are
+/// settled by whoever holds their position token.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ObligationPermissions {
+    /// Add collateral to a position without borrowing
+    pub deposit: bool,
+    /// Borrow liquidity against a position
+    pub borrow: bool,
+    /// Move collateral out of a position
+    pub withdraw: bool,
+    /// Repay a position, returning its collateral to the owner
+    pub repay: bool,
+}
+
+impl Space for ObligationPermissions {
+    const INIT_SPACE: usize = 1 + 1 + 1 + 1;
+}
+
+impl ObligationPermissions {
+    /// Whether the permissions cover the action
+    pub fn allows(&self, action: ObligationAction) -> bool {
+        match action {
+            ObligationAction::Deposit => self.deposit,
+            ObligationAction::Borrow => self.borrow,
+            ObligationAction::Withdraw => self.withdraw,
+            ObligationAction::Repay => self.repay,
+        }
+    }
+}
+
+/// Actions on an obligation gated by the owner or a delegate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObligationAction {
+    Deposit,
+    Borrow,
+    Withdraw,
+    Repay,
+}
+
 /// Obligation Position
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
 pub struct ObligationPosition {
@@ -150,34 +388,87 @@ pub struct ObligationPosition {
     pub weighted_deposited_amount: u64,
     /// Amount of Sol borrowed
     pub borrowed_amount: u64,
+    /// Amount of Sol deposited in `pending_epoch`, not counted until the next epoch refresh
+    pub pending_deposited_amount: u64,
+    /// Amount of collateral deposited in `pending_epoch` (Weighted)
+    pub pending_weighted_deposited_amount: u64,
+    /// Epoch of the pending deposits
+    pub pending_epoch: Epoch,
+    /// Mint of the token representing the position, if tokenized
+    pub position_mint: Option<Pubkey>,
 }
 
 impl Space for ObligationPosition {
-    const INIT_SPACE: usize = 8 + 32 + LoanType::INIT_SPACE + 8 + 8 + 8;
+    const INIT_SPACE: usize = 32 + LoanType::INIT_SPACE + 8 + 8 + 8 + 8 + 8 + 8 + 1 + 32;
 }
 
 impl ObligationPosition {
-    /// Create new obligation collateral
+    /// Create new obligation collateral, pending until the next epoch refresh
     pub fn new(params: InitObligationPositionParams) -> Self {
         Self {
             vote_account: params.vote_account,
             loan_type: params.loan_type,
-            deposited_amount: params.deposited_amount,
-            weighted_deposited_amount: params.weighted_deposited_amount,
+            deposited_amount: 0,
+            weighted_deposited_amount: 0,
             borrowed_amount: params.borrowed_amount,
+            pending_deposited_amount: params.deposited_amount,
+            pending_weighted_deposited_amount: params.weighted_deposited_amount,
+            pending_epoch: params.loan_type.epoch(),
+            position_mint: None,
         }
     }
 
-    /// Increase deposited collateral
-    pub fn deposit(&mut self, collateral_amount: u64, weighted_collateral_amount: u64) -> Result<()> {
-        self.deposited_amount = self
-            .deposited_amount
+    /// Count the pending collateral once the reserve is refreshed in a later epoch
+    pub fn settle_pending(&mut self, refreshed_epoch: Epoch) -> Result<()> {
+        if self.pending_weighted_deposited_amount == 0 || refreshed_epoch <= self.pending_epoch {
+            return Ok(());
+        }
+
+        self.deposited_amount = self.deposited_amount
+            .checked_add(self.pending_deposited_amount)
+            .ok_or(LendingError::MathOverflow)?;
+        self.weighted_deposited_amount = self.weighted_deposited_amount
+            .checked_add(self.pending_weighted_deposited_amount)
+            .ok_or(LendingError::MathOverflow)?;
+        self.pending_deposited_amount = 0;
+        self.pending_weighted_deposited_amount = 0;
+
+        Ok(())
+    }
+
+    /// Deposited amount, pending collateral included
+    pub fn total_deposited_amount(&self) -> Result<u64> {
+        self.deposited_amount
+            .checked_add(self.pending_deposited_amount)
+            .ok_or_else(|| LendingError::MathOverflow.into())
+    }
+
+    /// Weighted deposited amount, pending collateral included
+    pub fn total_weighted_deposited_amount(&self) -> Result<u64> {
+        self.weighted_deposited_amount
+            .checked_add(self.pending_weighted_deposited_amount)
+            .ok_or_else(|| LendingError::MathOverflow.into())
+    }
+
+    /// Record the mint of the token representing the position
+    pub fn tokenize(&mut self, position_mint: Pubkey) -> Result<()> {
+        require!(self.position_mint.is_none(), LendingError::PositionTokenized);
+        self.position_mint = Some(position_mint);
+
+        Ok(())
+    }
+
+    /// Increase deposited collateral, pending until the next epoch refresh
+    pub fn deposit(&mut self, collateral_amount: u64, weighted_collateral_amount: u64, current_epoch: Epoch) -> Result<()> {
+        self.pending_deposited_amount = self
+            .pending_deposited_amount
             .checked_add(collateral_amount)
             .ok_or(LendingError::MathOverflow)?;
-        self.weighted_deposited_amount = self
-            .weighted_deposited_amount
+        self.pending_weighted_deposited_amount = self
+            .pending_weighted_deposited_amount
             .checked_add(weighted_collateral_amount)
             .ok_or(LendingError::MathOverflow)?;
+        self.pending_epoch = current_epoch;
 
         Ok(())
     }
@@ -187,7 +478,7 @@ impl ObligationPosition {
         let new_deposited = self.deposited_amount.checked_sub(collateral_amount)
             .ok_or(LendingError::InsufficientCollateral)?;
 
-        self.check_ltv(self.borrowed_amount, new_deposited)?;
+        self.check_ltv(self.borrowed_amount, new_deposited, self.loan_type.max_ratio())?;
 
         self.deposited_amount = new_deposited;
         self.weighted_deposited_amount = self.weighted_deposited_amount
@@ -197,31 +488,39 @@ impl ObligationPosition {
         Ok(())
     }
 
-    /// Increase borrowed liquidity
-    pub fn borrow(&mut self, borrow_amount: u64) -> Result<()> {
+    /// Increase borrowed liquidity, up to the given max ratio
+    pub fn borrow(&mut self, borrow_amount: u64, max_ratio: u64) -> Result<()> {
         let new_borrowed = self.borrowed_amount.checked_add(borrow_amount)
             .ok_or(LendingError::MathOverflow)?;
 
-        self.check_ltv(new_borrowed, self.deposited_amount)?;
+        self.check_ltv(new_borrowed, self.deposited_amount, max_ratio)?;
 
         self.borrowed_amount = new_borrowed;
         Ok(())
     }
 
-    /// Check the Loan to Value ratio of the position after a change
-    fn check_ltv(&self, new_borrowed: u64, new_deposited: u64) -> Result<()> {
-        let ltv = new_borrowed
-            .checked_mul(100)
-            .and_then(|v| v.checked_div(new_deposited))
+    /// Increase borrowed liquidity against the current value of the collateral, up to the
+    /// given max ratio. The borrows must stay covered by the deposit
+    pub fn borrow_against_value(&mut self, borrow_amount: u64, collateral_value: u64, max_ratio: u64) -> Result<()> {
+        let new_borrowed = self.borrowed_amount.checked_add(borrow_amount)
             .ok_or(LendingError::MathOverflow)?;
 
-        let max_ratio = match self.loan_type {
-            LoanType::SHORT(_) => SHORT_MAX_RATIO,
-            LoanType::MEDIUM(_) => MEDIUM_MAX_RATIO,
-            LoanType::LONG(_) => LONG_MAX_RATIO,
-        };
+        self.check_ltv(new_borrowed, collateral_value, max_ratio)?;
+        self.check_ltv(new_borrowed, self.deposited_amount, MAX_BASIS_POINTS)?;
 
-        if ltv > max_ratio {
+        self.borrowed_amount = new_borrowed;
+        Ok(())
+    }
+
+    /// Check the Loan to Value ratio of the position after a change, the max ratio is in bps
+    fn check_ltv(&self, new_borrowed: u64, new_deposited: u64, max_ratio: u64) -> Result<()> {
+        if new_borrowed == 0 {
+            return Ok(());
+        }
+        require_neq!(new_deposited, 0, LendingError::LoanToValueTooHigh);
+
+        let ltv = FixedPrecision::from_ratio(new_borrowed as u128, new_deposited as u128, Rounding::Up)?;
+        if ltv > FixedPrecision::from_bps(max_ratio) {
             return Err(error!(LendingError::LoanToValueTooHigh));
         }
 
@@ -229,7 +528,7 @@ impl ObligationPosition {
     }
 
     /// Checks the health of the deposit and returns the amount after fees and if it's liquidatable
-    pub fn get_deposit_status(&self, current_epoch: Epoch) -> Result<(bool, u64)> {
+    pub fn get_deposit_status(&self, current_epoch: Epoch, config: &LendingMarketConfig) -> Result<(bool, u64)> {
         let (start_epoch, loan_duration) = match self.loan_type {
             LoanType::SHORT(epoch) => (epoch, SHORT_LOAN_DURATION),
             LoanType::MEDIUM(epoch) => (epoch, MEDIUM_LOAN_DURATION),
@@ -245,42 +544,75 @@ impl ObligationPosition {
         }
     
         // After loan duration: apply late fees
-        let late_epochs = epoch_elapsed - loan_duration;
-        let fee_percentage = 100u64.saturating_sub(late_epochs.saturating_mul(LATE_FEE));
-        
-        let deposited_amount_after_fees = self.deposited_amount
-            .saturating_mul(fee_percentage)
-            .checked_div(100)
-            .ok_or(LendingError::MathOverflow)?;
+        let late_fee_bps = config.late_fee_schedule(&self.loan_type).fee_bps(epoch_elapsed - loan_duration);
+        let remaining_bps = MAX_BASIS_POINTS.saturating_sub(late_fee_bps);
+
+        let deposited_amount_after_fees = u64::try_from((self.deposited_amount as u128)
+            .checked_mul(remaining_bps as u128)
+            .and_then(|v| v.checked_div(MAX_BASIS_POINTS as u128))
+            .ok_or(LendingError::MathOverflow)?
+        ).map_err(|_| LendingError::MathOverflow)?;
     
         let is_liquidatable = self.borrowed_amount > deposited_amount_after_fees;
     
         Ok((is_liquidatable, if is_liquidatable { self.borrowed_amount } else { deposited_amount_after_fees }))
     }
 
-    ///
-    pub fn get_ltv_to_max_ratio(&self) -> Result<u64> {        
-        let ltv = self.borrowed_amount
-            .checked_mul(WAD) 
-            .and_then(|v| v.checked_div(self.deposited_amount))  
-            .ok_or(LendingError::MathOverflow)?;
-    
-        let max_ratio = match self.loan_type {
-            LoanType::SHORT(_) => SHORT_MAX_RATIO,
-            LoanType::MEDIUM(_) => MEDIUM_MAX_RATIO,
-            LoanType::LONG(_) => LONG_MAX_RATIO,
+    /// Fees collected when settling the position: the reward share earned on the
+    /// collateral, unless waived, with the minimum fee as a floor, plus the late fees
+    pub fn settlement_quote(&self, params: SettlementQuoteParams) -> Result<SettlementQuote> {
+        let reward_share = if params.is_fee_waived { 0 } else {
+            self.get_ltv_to_max_ratio()?
+                .apply(params.stake_current_value.saturating_sub(self.deposited_amount), Rounding::Up)?
         };
-    
-        let normalized_ltv = ltv
-            .checked_mul(100) 
-            .and_then(|v| v.checked_div(max_ratio)) 
+
+        let minimum_fee = FixedPrecision::from_bps(params.minimum_fee_bps)
+            .apply(self.borrowed_amount, Rounding::Up)?;
+
+        let late_fee = self.deposited_amount
+            .checked_sub(params.deposited_amount_after_fee)
             .ok_or(LendingError::MathOverflow)?;
-    
-        Ok(u64::from(normalized_ltv
-            .checked_div(WAD)
+
+        // The fees can't take more than the collateral
+        let fee = reward_share.max(minimum_fee)
+            .checked_add(late_fee)
             .ok_or(LendingError::MathOverflow)?
-        ))
-        
+            .min(params.stake_current_value);
+
+        Ok(SettlementQuote {
+            reward_share,
+            minimum_fee,
+            late_fee,
+            fee,
+            returned_amount: params.stake_current_value - fee,
+        })
+    }
+
+    /// Restart the position on a new loan type, with the collateral left after settlement
+    pub fn rollover(&mut self, loan_type: LoanType, deposited_amount: u64, weighted_deposited_amount: u64, max_ratio: u64) -> Result<()> {
+        require!(self.pending_weighted_deposited_amount == 0, LendingError::PendingCollateral);
+        self.check_ltv(self.borrowed_amount, deposited_amount, max_ratio)?;
+
+        self.loan_type = loan_type;
+        self.deposited_amount = deposited_amount;
+        self.weighted_deposited_amount = weighted_deposited_amount;
+
+        Ok(())
+    }
+
+    /// Share of the max LTV ratio of the loan type used by the position, capped at 100%
+    pub fn get_ltv_to_max_ratio(&self) -> Result<FixedPrecision> {
+        if self.borrowed_amount == 0 {
+            return Ok(FixedPrecision::default());
+        }
+
+        let ltv = FixedPrecision::from_ratio(self.borrowed_amount as u128, self.deposited_amount as u128, Rounding::Up)?;
+        let max_ratio = FixedPrecision::from_bps(self.loan_type.max_ratio());
+        if ltv >= max_ratio {
+            return Ok(FixedPrecision::from_wad(WAD));
+        }
+
+        ltv.checked_div_rounded(&max_ratio, Rounding::Up)
     }
 }
 
@@ -296,6 +628,10 @@ pub struct InitObligationPositionParams {
     pub weighted_deposited_amount: u64,
     /// Borrowed Amount
     pub borrowed_amount: u64,
+    /// Max LTV ratio allowed by the Reserve, in bps
+    pub max_ratio: u64,
+    /// Last epoch the Reserve was refreshed in
+    pub refreshed_epoch: Epoch,
 }
 
 /// An enum representing the types of status Borrowing of the Stake Account can go trough
@@ -320,6 +656,16 @@ impl Default for LoanType {
 }
 
 impl LoanType {
+    /// Create a Loan Type from its index, starting at the given epoch
+    pub fn new(loan_type: u8, start_epoch: Epoch) -> Result<Self> {
+        match loan_type {
+            0 => Ok(LoanType::SHORT(start_epoch)),
+            1 => Ok(LoanType::MEDIUM(start_epoch)),
+            2 => Ok(LoanType::LONG(start_epoch)),
+            _ => Err(LendingError::InvalidLoanType.into()),
+        }
+    }
+
     /// Get the epoch of the Epoch Status
     pub fn epoch(&self) -> u64 {
         match self {
@@ -328,6 +674,422 @@ impl LoanType {
             Self::LONG(epoch) => *epoch,
         }
     }
+
+    /// Check if both Loan Types have the same duration, whatever their starting epoch
+    pub fn is_same_kind(&self, other: &LoanType) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// Get the max LTV ratio of the Loan Type, in bps
+    pub fn max_ratio(&self) -> u64 {
+        match self {
+            Self::SHORT(_) => SHORT_MAX_RATIO,
+            Self::MEDIUM(_) => MEDIUM_MAX_RATIO,
+            Self::LONG(_) => LONG_MAX_RATIO,
+        }
+    }
 }
 
-// Note: we don't need to calculate the interest because it'100% of the rewards of 1/TVL * borrowed amount
\ No newline at end of file
+// Note: we don't need to calculate the interest because it'100% of the rewards of 1/TVL * borrowed amount
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(loan_type: LoanType, deposited_amount: u64, borrowed_amount: u64) -> ObligationPosition {
+        ObligationPosition {
+            vote_account: Pubkey::new_unique(),
+            loan_type,
+            deposited_amount,
+            weighted_deposited_amount: deposited_amount,
+            borrowed_amount,
+            pending_deposited_amount: 0,
+            pending_weighted_deposited_amount: 0,
+            pending_epoch: 0,
+            position_mint: None,
+        }
+    }
+
+    #[test]
+    fn test_check_ltv_rounds_against_the_borrower() {
+        // (borrowed, deposited, max ratio in bps, allowed)
+        let cases: &[(u64, u64, u64, bool)] = &[
+            (0, 0, SHORT_MAX_RATIO, true),
+            (1, 0, SHORT_MAX_RATIO, false),
+            (0, 1_000, 0, true),
+            (1, 1_000, 0, false),
+            (900, 1_000, SHORT_MAX_RATIO, true),
+            // 90.1% used to truncate to 90%
+            (901, 1_000, SHORT_MAX_RATIO, false),
+            (9_099, 10_000, SHORT_MAX_RATIO, false),
+            // 90% + 1 lamport out of u64::MAX
+            (u64::MAX / 10 * 9, u64::MAX / 10 * 10, SHORT_MAX_RATIO, true),
+            (u64::MAX / 10 * 9 + 1, u64::MAX / 10 * 10, SHORT_MAX_RATIO, false),
+            (2, 3, 6_666, false),
+            (2, 3, 6_667, true),
+            (1_000, 1_000, MAX_BASIS_POINTS, true),
+            (1_001, 1_000, MAX_BASIS_POINTS, false),
+            (u64::MAX, u64::MAX, MAX_BASIS_POINTS, true),
+        ];
+
+        for &(borrowed, deposited, max_ratio, allowed) in cases {
+            let position = position(LoanType::SHORT(0), deposited, 0);
+            assert_eq!(
+                position.check_ltv(borrowed, deposited, max_ratio).is_ok(),
+                allowed,
+                "borrowed {borrowed}, deposited {deposited}, max ratio {max_ratio}",
+            );
+        }
+    }
+
+    #[test]
+    fn test_ltv_to_max_ratio_rounds_up_and_caps() {
+        // (loan type, deposited, borrowed, expected ratio in WAD)
+        let cases: &[(LoanType, u64, u64, u128)] = &[
+            (LoanType::SHORT(0), 1_000, 0, 0),
+            (LoanType::SHORT(0), 1_000, 450, WAD as u128 / 2),
+            (LoanType::SHORT(0), 1_000, 900, WAD as u128),
+            (LoanType::MEDIUM(0), 1_000, 400, WAD as u128 / 2),
+            (LoanType::LONG(0), 1_000, 700, WAD as u128),
+            // 1/3 of the max ratio, rounded up
+            (LoanType::SHORT(0), 3_000, 900, WAD as u128 / 3 + 1),
+            // Above the max ratio of the loan type
+            (LoanType::LONG(0), 1_000, 900, WAD as u128),
+            (LoanType::SHORT(0), u64::MAX, u64::MAX, WAD as u128),
+        ];
+
+        for &(loan_type, deposited, borrowed, expected) in cases {
+            let ratio = position(loan_type, deposited, borrowed).get_ltv_to_max_ratio().unwrap();
+            assert_eq!(ratio, FixedPrecision(expected), "deposited {deposited}, borrowed {borrowed}");
+        }
+
+        assert_eq!(position(LoanType::SHORT(0), 0, 0).get_ltv_to_max_ratio().unwrap(), FixedPrecision(0));
+        assert!(position(LoanType::SHORT(0), 0, 1).get_ltv_to_max_ratio().is_err());
+    }
+
+    fn quote(position: &ObligationPosition, stake_current_value: u64, deposited_amount_after_fee: u64, is_fee_waived: bool, minimum_fee_bps: u64) -> SettlementQuote {
+        position.settlement_quote(SettlementQuoteParams {
+            stake_current_value,
+            deposited_amount_after_fee,
+            is_fee_waived,
+            minimum_fee_bps,
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_settlement_fee_rounds_up() {
+        // (deposited, borrowed, stake value, deposited after late fees, waived, expected fee)
+        let cases: &[(u64, u64, u64, u64, bool, u64)] = &[
+            (1_000, 900, 1_100, 1_000, false, 100),
+            (1_000, 450, 1_100, 1_000, false, 50),
+            (1_000, 450, 1_101, 1_000, false, 51),
+            (3_000, 900, 3_001, 3_000, false, 1),
+            (1_000, 0, 1_100, 1_000, false, 0),
+            (1_000, 900, 900, 1_000, false, 0),
+            (1_000, 900, 1_100, 1_000, true, 0),
+            (1_000, 900, 1_100, 990, false, 110),
+            (1_000, 900, 1_100, 990, true, 10),
+        ];
+
+        for &(deposited, borrowed, stake_value, deposited_after_fee, waived, expected) in cases {
+            let fee = quote(&position(LoanType::SHORT(0), deposited, borrowed), stake_value, deposited_after_fee, waived, 0).fee;
+            assert_eq!(fee, expected, "deposited {deposited}, borrowed {borrowed}, stake value {stake_value}");
+        }
+    }
+
+    fn cross_collateral_obligation(positions: Vec<ObligationPosition>) -> (Obligation, Vec<PositionCollateral>) {
+        let collaterals = positions.iter()
+            .map(|position| PositionCollateral {
+                vote_account: position.vote_account,
+                collateral_value: position.deposited_amount,
+                max_ratio: position.loan_type.max_ratio(),
+            })
+            .collect();
+        let obligation = Obligation { cross_collateral: true, positions, ..Obligation::default() };
+        (obligation, collaterals)
+    }
+
+    #[test]
+    fn test_health_factor_over_all_positions() {
+        // Short position at 0% LTV backs a long position at 100% LTV
+        let (obligation, collaterals) = cross_collateral_obligation(vec![
+            position(LoanType::SHORT(0), 1_000, 0),
+            position(LoanType::LONG(0), 1_000, 1_000),
+        ]);
+        assert_eq!(obligation.borrowed_amount().unwrap(), 1_000);
+        assert_eq!(obligation.borrowing_capacity(&collaterals).unwrap(), 900 + 700);
+        assert_eq!(obligation.health_factor(&collaterals).unwrap(), Some(FixedPrecision(WAD as u128 * 16 / 10)));
+        assert!(obligation.check_health(&collaterals).is_ok());
+
+        // Borrows above the aggregate capacity
+        let (obligation, collaterals) = cross_collateral_obligation(vec![
+            position(LoanType::SHORT(0), 1_000, 601),
+            position(LoanType::LONG(0), 1_000, 1_000),
+        ]);
+        assert!(obligation.check_health(&collaterals).is_err());
+
+        // Without borrows there is no health factor
+        let (obligation, collaterals) = cross_collateral_obligation(vec![position(LoanType::SHORT(0), 1_000, 0)]);
+        assert_eq!(obligation.health_factor(&collaterals).unwrap(), None);
+        assert!(obligation.check_health(&collaterals).is_ok());
+
+        // Collaterals must match the positions
+        let (obligation, mut collaterals) = cross_collateral_obligation(vec![
+            position(LoanType::SHORT(0), 1_000, 0),
+            position(LoanType::LONG(0), 1_000, 100),
+        ]);
+        collaterals.swap(0, 1);
+        assert!(obligation.check_health(&collaterals).is_err());
+        assert!(obligation.check_health(&collaterals[..1]).is_err());
+    }
+
+    #[test]
+    fn test_switch_cross_collateral_mode() {
+        let (mut obligation, collaterals) = cross_collateral_obligation(vec![
+            position(LoanType::SHORT(0), 1_000, 0),
+            position(LoanType::LONG(0), 1_000, 1_000),
+        ]);
+        assert_eq!(obligation.position_max_ratio(LONG_MAX_RATIO), MAX_BASIS_POINTS);
+
+        // The long position doesn't stand alone
+        assert!(obligation.set_cross_collateral(false, &collaterals).is_err());
+
+        obligation.positions[1].borrowed_amount = 700;
+        obligation.set_cross_collateral(false, &collaterals).unwrap();
+        assert!(!obligation.cross_collateral);
+        assert_eq!(obligation.position_max_ratio(LONG_MAX_RATIO), LONG_MAX_RATIO);
+
+        obligation.set_cross_collateral(true, &collaterals).unwrap();
+        assert!(obligation.cross_collateral);
+    }
+
+    #[test]
+    fn test_borrow_against_collateral_value() {
+        // (deposited, borrowed, collateral value, borrow amount, allowed)
+        let cases: &[(u64, u64, u64, u64, bool)] = &[
+            (1_000, 900, 1_000, 1, false),
+            // Appreciated collateral raises the limit
+            (1_000, 900, 1_050, 45, true),
+            (1_000, 900, 1_050, 46, false),
+            // Borrows can't exceed the deposit
+            (1_000, 900, 1_200, 100, true),
+            (1_000, 900, 1_200, 101, false),
+            (1_000, 0, 900, 810, true),
+            (1_000, 0, 900, 811, false),
+        ];
+
+        for &(deposited, borrowed, collateral_value, borrow_amount, allowed) in cases {
+            let mut position = position(LoanType::SHORT(0), deposited, borrowed);
+            let result = position.borrow_against_value(borrow_amount, collateral_value, SHORT_MAX_RATIO);
+            assert_eq!(result.is_ok(), allowed, "collateral value {collateral_value}, borrow amount {borrow_amount}");
+            let expected_borrowed = if allowed { borrowed + borrow_amount } else { borrowed };
+            assert_eq!(position.borrowed_amount, expected_borrowed);
+        }
+    }
+
+    #[test]
+    fn test_settlement_minimum_fee() {
+        let fees = ReserveFees::default();
+        let position = position(LoanType::SHORT(10), 1_000, 900);
+
+        // Same epoch, no rewards accrued
+        let same_epoch = quote(&position, 1_000, 1_000, false, fees.minimum_fee_bps(10, 10));
+        assert_eq!(same_epoch, SettlementQuote { reward_share: 0, minimum_fee: 9, late_fee: 0, fee: 9, returned_amount: 991 });
+
+        // Later epochs, the reward share is below the minimum fee
+        let low_rewards = quote(&position, 1_000, 1_000, false, fees.minimum_fee_bps(10, 11));
+        assert_eq!(low_rewards, SettlementQuote { reward_share: 0, minimum_fee: 1, late_fee: 0, fee: 1, returned_amount: 999 });
+
+        // The reward share above the minimum fee is charged alone
+        let high_rewards = quote(&position, 1_100, 1_000, false, fees.minimum_fee_bps(10, 11));
+        assert_eq!(high_rewards.fee, 100);
+
+        // The minimum fee still applies when the reward share is waived, on top of late fees
+        let waived = quote(&position, 1_100, 990, true, fees.minimum_fee_bps(10, 30));
+        assert_eq!(waived, SettlementQuote { reward_share: 0, minimum_fee: 1, late_fee: 10, fee: 11, returned_amount: 1_089 });
+
+        // Fees are capped by the collateral
+        let capped = quote(&position, 5, 1_000, false, MAX_BASIS_POINTS);
+        assert_eq!(capped, SettlementQuote { reward_share: 0, minimum_fee: 900, late_fee: 0, fee: 5, returned_amount: 0 });
+    }
+
+    #[test]
+    fn test_pending_collateral_counts_after_epoch_refresh() {
+        let params = |deposited_amount, borrowed_amount| InitObligationPositionParams {
+            vote_account: Pubkey::default(),
+            loan_type: LoanType::SHORT(10),
+            deposited_amount,
+            weighted_deposited_amount: deposited_amount,
+            borrowed_amount,
+            max_ratio: SHORT_MAX_RATIO,
+            refreshed_epoch: 10,
+        };
+
+        // Collateral deposited in the current epoch can't be borrowed against
+        let mut obligation = Obligation::default();
+        assert!(obligation.add_or_create_position(params(1_000, 1), 10).is_err());
+        obligation.add_or_create_position(params(1_000, 0), 10).unwrap();
+        obligation.add_or_create_position(params(500, 0), 10).unwrap();
+
+        let position = obligation.find_position_mut(Pubkey::default()).unwrap();
+        assert_eq!((position.deposited_amount, position.pending_deposited_amount, position.pending_epoch), (0, 1_500, 10));
+        assert_eq!(position.total_weighted_deposited_amount().unwrap(), 1_500);
+        assert!(position.borrow(1, SHORT_MAX_RATIO).is_err());
+
+        // Pending collateral is left out of the reward share
+        let pending_quote = quote(position, 0, 0, false, 0);
+        assert_eq!(pending_quote.fee, 0);
+
+        // Still pending until the reserve is refreshed in a later epoch
+        position.settle_pending(10).unwrap();
+        assert_eq!(position.pending_deposited_amount, 1_500);
+        assert!(position.rollover(LoanType::SHORT(10), 1_500, 1_500, SHORT_MAX_RATIO).is_err());
+
+        position.settle_pending(11).unwrap();
+        assert_eq!((position.deposited_amount, position.weighted_deposited_amount), (1_500, 1_500));
+        assert_eq!((position.pending_deposited_amount, position.pending_weighted_deposited_amount), (0, 0));
+        position.borrow(1_350, SHORT_MAX_RATIO).unwrap();
+        assert!(position.borrow(1, SHORT_MAX_RATIO).is_err());
+    }
+
+    #[test]
+    fn test_new_position_is_borrowed_against_after_epoch_refresh() {
+        let params = |borrowed_amount| InitObligationPositionParams {
+            vote_account: Pubkey::default(),
+            loan_type: LoanType::SHORT(10),
+            deposited_amount: 1_000,
+            weighted_deposited_amount: 1_000,
+            borrowed_amount,
+            max_ratio: SHORT_MAX_RATIO,
+            refreshed_epoch: 10,
+        };
+
+        // A new position can't borrow against its pending deposit
+        let mut obligation = Obligation::default();
+        let error = obligation.add_or_create_position(params(1), 10).unwrap_err();
+        assert_eq!(error, LendingError::PendingCollateral.into());
+        assert!(obligation.positions.is_empty());
+
+        // Deposit first, the borrow is rejected until the reserve is refreshed in a later epoch
+        obligation.add_or_create_position(params(0), 10).unwrap();
+        let position = obligation.find_position_mut(Pubkey::default()).unwrap();
+        position.settle_pending(10).unwrap();
+        assert!(position.borrow_against_value(900, 1_000, SHORT_MAX_RATIO).is_err());
+
+        // Then borrow against the settled collateral
+        position.settle_pending(11).unwrap();
+        position.borrow_against_value(900, 1_000, SHORT_MAX_RATIO).unwrap();
+        assert_eq!(position.borrowed_amount, 900);
+    }
+
+    #[test]
+    fn test_settlement_is_bound_to_the_obligation() {
+        let owner = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let holder = Pubkey::new_unique();
+        let untokenized = position(LoanType::SHORT(0), 1_000, 500);
+        let mut tokenized = position(LoanType::SHORT(0), 1_000, 500);
+        tokenized.position_mint = Some(Pubkey::new_unique());
+        let (untokenized_vote, tokenized_vote) = (untokenized.vote_account, tokenized.vote_account);
+
+        let mut obligation = Obligation { owner, positions: vec![untokenized, tokenized], ..Obligation::default() };
+        obligation.delegate = Some(ObligationDelegate {
+            authority: delegate,
+            permissions: ObligationPermissions { repay: true, ..ObligationPermissions::default() },
+        });
+
+        // The collateral of an untokenized position goes back to the owner, whoever settles it
+        assert_eq!(obligation.settlement_recipient(owner, untokenized_vote).unwrap(), owner);
+        assert_eq!(obligation.settlement_recipient(delegate, untokenized_vote).unwrap(), owner);
+        // Holding collateral tokens of the same reserve isn't enough
+        assert!(obligation.settlement_recipient(holder, untokenized_vote).is_err());
+
+        // A tokenized position follows its token holder
+        assert_eq!(obligation.settlement_recipient(holder, tokenized_vote).unwrap(), holder);
+
+        obligation.delegate.as_mut().unwrap().permissions.repay = false;
+        assert!(obligation.settlement_recipient(delegate, untokenized_vote).is_err());
+    }
+
+    #[test]
+    fn test_delegate_permission_scopes() {
+        let owner = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let actions = [ObligationAction::Deposit, ObligationAction::Borrow, ObligationAction::Withdraw, ObligationAction::Repay];
+        // (permissions, allowed actions in the order above)
+        let cases = [
+            (ObligationPermissions::default(), [false, false, false, false]),
+            (ObligationPermissions { deposit: true, ..ObligationPermissions::default() }, [true, false, false, false]),
+            (ObligationPermissions { borrow: true, ..ObligationPermissions::default() }, [false, true, false, false]),
+            (ObligationPermissions { withdraw: true, ..ObligationPermissions::default() }, [false, false, true, false]),
+            (ObligationPermissions { repay: true, ..ObligationPermissions::default() }, [false, false, false, true]),
+            // May repay and add collateral but not borrow or withdraw
+            (ObligationPermissions { deposit: true, repay: true, ..ObligationPermissions::default() }, [true, false, false, true]),
+        ];
+
+        for (permissions, allowed) in cases {
+            let obligation = Obligation {
+                owner,
+                delegate: Some(ObligationDelegate { authority: delegate, permissions }),
+                ..Obligation::default()
+            };
+            for (action, allowed) in actions.into_iter().zip(allowed) {
+                assert_eq!(obligation.check_authority(delegate, action).is_ok(), allowed, "{:?} {:?}", permissions, action);
+                // The owner can always act, anyone else never
+                assert!(obligation.check_authority(owner, action).is_ok());
+                assert!(obligation.check_authority(Pubkey::new_unique(), action).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_obligation_space_matches_its_layout() {
+        let mut positions = vec![position(LoanType::SHORT(0), 1_000, 500), position(LoanType::LONG(0), 1_000, 500)];
+        for position in positions.iter_mut() {
+            position.position_mint = Some(Pubkey::new_unique());
+        }
+        let obligation = Obligation {
+            delegate: Some(ObligationDelegate { authority: Pubkey::new_unique(), permissions: ObligationPermissions::default() }),
+            positions,
+            ..Obligation::default()
+        };
+
+        // The space of the largest layout, with the 8-byte account discriminator
+        assert_eq!(obligation.try_to_vec().unwrap().len() + 8, Obligation::space(2));
+    }
+
+    #[test]
+    fn test_deposits_across_epochs_keep_matured_collateral() {
+        let params = |epoch, refreshed_epoch, deposited_amount, borrowed_amount| InitObligationPositionParams {
+            vote_account: Pubkey::default(),
+            loan_type: LoanType::SHORT(epoch),
+            deposited_amount,
+            weighted_deposited_amount: deposited_amount,
+            borrowed_amount,
+            max_ratio: SHORT_MAX_RATIO,
+            refreshed_epoch,
+        };
+
+        let mut obligation = Obligation::default();
+        obligation.add_or_create_position(params(10, 10, 1_000, 0), 10).unwrap();
+
+        // The first deposit matured with the refresh of epoch 11 and backs a borrow along the second one
+        obligation.add_or_create_position(params(11, 11, 500, 900), 10).unwrap();
+        let position = obligation.find_position(Pubkey::default()).unwrap().0;
+        assert!(position.loan_type == LoanType::SHORT(10));
+        assert_eq!((position.deposited_amount, position.weighted_deposited_amount), (1_000, 1_000));
+        assert_eq!((position.pending_deposited_amount, position.pending_epoch), (500, 11));
+        assert_eq!(position.borrowed_amount, 900);
+
+        // The second deposit still can't be borrowed against
+        assert!(obligation.add_or_create_position(params(11, 11, 0, 1), 10).is_err());
+
+        // Until the reserve is refreshed in a later epoch
+        obligation.add_or_create_position(params(12, 12, 0, 450), 10).unwrap();
+        let position = obligation.find_position(Pubkey::default()).unwrap().0;
+        assert_eq!((position.deposited_amount, position.pending_deposited_amount), (1_500, 0));
+        assert_eq!(position.borrowed_amount, 1_350);
+
+        // Another loan type can't be added to the position
+        assert!(obligation.add_or_create_position(InitObligationPositionParams { loan_type: LoanType::LONG(12), ..params(12, 12, 100, 0) }, 10).is_err());
+    }
+}
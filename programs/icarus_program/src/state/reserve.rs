@@ -1,8 +1,8 @@
 pub use {
     super::*,
     anchor_lang::prelude::*,
-    solana_program::{clock::{Epoch, Slot}, native_token::LAMPORTS_PER_SOL},
-    crate::error::LendingError,
+    solana_program::{clock::{Epoch, Slot}, native_token::LAMPORTS_PER_SOL, vote::state::VoteState},
+    crate::{get_epochs_without_credits, get_epoch_credits, error::LendingError, LIQUIDITY_MINT_PREFIX, COLLATERAL_MINT_PREFIX},
 };
 
 /// How does `Reserve` work for Icarus:
@@ -26,16 +26,32 @@ pub struct Reserve {
     pub liquidity: ReserveLiquidity,
     /// Reserve Collateral
     pub collateral: ReserveCollateral,
+    /// Reserve Stake Rewards
+    pub rewards: ReserveRewards,
     /// Bump seed for Reserve
     pub bump: u8,
     /// Bump seed for the Stake Account
     pub stake_bump: u8,
     /// Bump seed for the Vault Account
     pub vault_bump: u8,
+    /// Bump seed for the Liquidity Mint, zero for the keypair mint of a migrated reserve
+    pub liquidity_mint_bump: u8,
+    /// Bump seed for the Collateral Mint, zero for the keypair mint of a migrated reserve
+    pub collateral_mint_bump: u8,
+    /// Minimum fees charged when positions are settled
+    pub fees: ReserveFees,
+    /// Reserve Validator, last so the fields above keep their offsets
+    pub validator: ReserveValidator,
+}
+
+impl Versioned for Reserve {
+    fn version(&self) -> u8 {
+        self.version
+    }
 }
 
 impl Space for Reserve {
-    const INIT_SPACE: usize = 8 + 1 + 32 + 32 + 8 + LastUpdate::INIT_SPACE + ReserveLiquidity::INIT_SPACE + ReserveCollateral::INIT_SPACE + 1 + 1 + 1 + 128;
+    const INIT_SPACE: usize = 8 + 1 + 32 + 32 + 8 + LastUpdate::INIT_SPACE + ReserveLiquidity::INIT_SPACE + ReserveCollateral::INIT_SPACE + ReserveRewards::INIT_SPACE + 1 + 1 + 1 + 1 + 1 + ReserveFees::INIT_SPACE + ReserveValidator::INIT_SPACE + 128;
 }
 
 impl Reserve {
@@ -46,6 +62,39 @@ impl Reserve {
         reserve
     }
 
+    /// Deserialize an account stored with the layout of a previous version
+    pub fn migrate(version: u8, data: &[u8]) -> Result<Self> {
+        match version {
+            1 => Ok(ReserveV1::deserialize(&mut &data[..])?.into()),
+            _ => Err(LendingError::UnsupportedAccountVersion.into()),
+        }
+    }
+
+    /// Record the bumps of the mints derived from the reserve. Reserves migrated from version 1
+    /// keep their keypair mints with a zero bump, the mints are always checked by address
+    pub fn set_mint_bumps(&mut self, reserve_key: &Pubkey) {
+        let mint_bump = |prefix: &[u8], mint_pubkey: Pubkey| {
+            let (pda, bump) = Pubkey::find_program_address(&[prefix, reserve_key.as_ref()], &crate::ID);
+            if pda == mint_pubkey { bump } else { 0 }
+        };
+        self.liquidity_mint_bump = mint_bump(LIQUIDITY_MINT_PREFIX, self.liquidity.mint_pubkey);
+        self.collateral_mint_bump = mint_bump(COLLATERAL_MINT_PREFIX, self.collateral.mint_pubkey);
+    }
+
+    /// Check if the reward share of a loan started at the given epoch is waived after a commission rug
+    pub fn is_fee_waived(&self, config: &LendingMarketConfig, loan_start_epoch: Epoch) -> bool {
+        config.commission_rug_protection
+            && self.validator.is_commission_rugged_since(loan_start_epoch, config.max_commission_increase)
+    }
+
+    /// Address of one of the Reserve mints
+    pub fn mint_pubkey(&self, mint: ReserveMint) -> Pubkey {
+        match mint {
+            ReserveMint::Liquidity => self.liquidity.mint_pubkey,
+            ReserveMint::Collateral => self.collateral.mint_pubkey,
+        }
+    }
+
     /// Initialize a reserve
     pub fn init(&mut self, params: InitReserveParams) {
         self.version = PROGRAM_VERSION;
@@ -55,9 +104,14 @@ impl Reserve {
         self.vote_account = params.vote_account;
         self.liquidity = params.liquidity;
         self.collateral = params.collateral;
+        self.validator = params.validator;
+        self.rewards = ReserveRewards::default();
         self.bump = params.bump;
         self.stake_bump = params.stake_bump;
         self.vault_bump = params.vault_bump;
+        self.liquidity_mint_bump = params.liquidity_mint_bump;
+        self.collateral_mint_bump = params.collateral_mint_bump;
+        self.fees = ReserveFees::default();
     }
 
     /// Record deposited liquidity and return amount of collateral tokens to mint
@@ -122,6 +176,84 @@ impl Reserve {
         Ok(liquidity_amount)
     }
 
+    /// Current value in SOL of collateral tokens, including the measured rewards
+    pub fn collateral_value(&self, token_amount: u64) -> Result<u64> {
+        self.calculate_liquidity_position(
+            token_amount,
+            self.collateral.collateral_amount,
+            self.collateral.mint_total_supply,
+        )
+    }
+
+    /// Measure the rewards earned by the reserve stake since the last snapshot and 
+    /// accrue the collateral share of them
+    pub fn accrue_rewards(&mut self, stake_lamports: u64, delegated_stake: u64, epochs_elapsed: u64) -> Result<()> {
+        self.rewards.measure(stake_lamports, delegated_stake, epochs_elapsed)?;
+
+        // Rewards of all the epochs elapsed go to the collateral
+        let collateral_rewards = (self.collateral.collateral_amount as u128)
+            .checked_mul(self.rewards.reward_rate_wads as u128)
+            .and_then(|v| v.checked_mul(epochs_elapsed as u128))
+            .and_then(|v| v.checked_div(WAD as u128))
+            .ok_or(LendingError::MathOverflow)?;
+
+        self.collateral.deposit(u64::try_from(collateral_rewards)?)?;
+
+        Ok(())
+    }
+
+    /// Move the reserve to the current epoch and return the stake operations to perform,
+    /// or None if the reserve was already refreshed in this epoch
+    pub fn refresh_epoch(&mut self, params: EpochRefreshParams) -> Result<Option<EpochRefreshActions>> {
+        let epochs_elapsed = self.epoch_elapsed(params.current_epoch)?;
+        if epochs_elapsed == 0 {
+            return Ok(None);
+        }
+
+        let mut actions = EpochRefreshActions::default();
+
+        // Record the validator performance
+        self.validator.update(params.vote_state, params.current_epoch, params.config);
+
+        // Measure the rewards earned by the reserve stake since the last refresh
+        self.accrue_rewards(params.stake_lamports, params.delegated_stake, epochs_elapsed)?;
+
+        // Settle the outstanding transient accounts, whatever epoch they were created in
+        if self.collateral.activating_stake_epoch.is_some() && params.is_activating_stake_active {
+            actions.merge_activating_stake = true;
+            self.collateral.activating_stake_epoch = None;
+        }
+
+        if self.collateral.deactivating_stake_epoch.is_some() && params.is_deactivating_stake_inactive {
+            actions.claim_deactivated_stake = true;
+            self.collateral.deactivating_stake_epoch = None;
+            self.liquidity.claim(
+                params.deactivated_stake_amount,
+                std::mem::take(&mut self.collateral.deactivating_borrowed_amount),
+                params.config.protocol_fee_percent,
+            )?;
+        }
+
+        // Open new transient accounts keyed by the current epoch, one of each kind at a time 
+        // so none can be orphaned
+        if self.collateral.activating_stake_epoch.is_none() && params.inactive_stake_amount > params.minimum_stake_amount {
+            actions.activate_amount = params.inactive_stake_amount;
+            self.collateral.activating_stake_epoch = Some(params.current_epoch);
+        }
+
+        if self.collateral.deactivating_stake_epoch.is_none() && self.collateral.collateral_amount_to_claim > 0 {
+            actions.deactivate_amount = self.collateral.collateral_amount_to_claim;
+            self.collateral.deactivating_stake_epoch = Some(params.current_epoch);
+            self.collateral.collateral_amount_to_claim = 0;
+            self.collateral.deactivating_borrowed_amount = std::mem::take(&mut self.collateral.borrowed_amount_to_claim);
+        }
+
+        // Jump straight to the current epoch
+        self.update_epoch(params.current_epoch);
+
+        Ok(Some(actions))
+    }
+
     /// Calculate pool tokens to mint, given total token supply, total liquidity, liquidity deposit
     pub fn calculate_token_position(
         &self,
@@ -171,6 +303,47 @@ impl Reserve {
         self.last_epoch = epoch;
     }
 
+    /// Epoch seed of the outstanding activating stake account
+    pub fn activating_stake_seed(&self) -> [u8; 8] {
+        self.collateral.activating_stake_epoch.unwrap_or(self.last_epoch).to_le_bytes()
+    }
+
+    /// Epoch seed of the outstanding deactivating stake account
+    pub fn deactivating_stake_seed(&self) -> [u8; 8] {
+        self.collateral.deactivating_stake_epoch.unwrap_or(self.last_epoch).to_le_bytes()
+    }
+
+    /// Check that the transient stake account of the given kind and epoch is spent: opened by an
+    /// earlier refresh and no longer outstanding, so it can be closed
+    pub fn check_transient_stake_closable(&self, kind: TransientStakeKind, epoch: Epoch) -> Result<()> {
+        let outstanding_epoch = match kind {
+            TransientStakeKind::Activating => self.collateral.activating_stake_epoch,
+            TransientStakeKind::Deactivating => self.collateral.deactivating_stake_epoch,
+        };
+        require!(epoch < self.last_epoch && outstanding_epoch != Some(epoch), LendingError::TransientStakeInUse);
+        Ok(())
+    }
+
+    /// Check that the reserve accepts new borrows, which are paused in reduced-risk mode
+    pub fn check_borrows_allowed(&self) -> Result<()> {
+        require!(!self.validator.reduced_risk, LendingError::ReserveReducedRisk);
+        Ok(())
+    }
+
+    /// Max LTV ratio, in bps, allowed for a loan type on this reserve, rounded down.
+    /// In reduced-risk mode it only applies to existing positions, on rollover and
+    /// in cross-collateral health checks, since new borrows are paused
+    pub fn max_ratio(&self, loan_type: &LoanType, config: &LendingMarketConfig) -> Result<u64> {
+        if !self.validator.reduced_risk {
+            return Ok(loan_type.max_ratio());
+        }
+
+        loan_type.max_ratio()
+            .checked_mul(config.reduced_risk_ltv_percent as u64)
+            .and_then(|v| v.checked_div(100))
+            .ok_or(LendingError::MathOverflow.into())
+    }
+
     // pub fn current_borrow_rate -- To be implemented
 
     // pub fn accrue_interest -- To be implemented
@@ -183,6 +356,77 @@ impl Reserve {
 
 }
 
+/// Observed state of the validator and the reserve stake accounts at an epoch refresh
+pub struct EpochRefreshParams<'a> {
+    /// Current epoch
+    pub current_epoch: Epoch,
+    /// Vote state of the reserve validator
+    pub vote_state: &'a VoteState,
+    /// Lending market config
+    pub config: &'a LendingMarketConfig,
+    /// Lamports of the reserve stake account
+    pub stake_lamports: u64,
+    /// Delegated stake of the reserve stake account
+    pub delegated_stake: u64,
+    /// Lamports of the reserve stake account that are neither delegated nor reserved for rent
+    pub inactive_stake_amount: u64,
+    /// Minimum lamports to open a new stake account
+    pub minimum_stake_amount: u64,
+    /// True if the outstanding activating stake account can be merged
+    pub is_activating_stake_active: bool,
+    /// True if the outstanding deactivating stake account can be claimed
+    pub is_deactivating_stake_inactive: bool,
+    /// Stake of the outstanding deactivating stake account, without its rent
+    pub deactivated_stake_amount: u64,
+}
+
+/// Kind of the transient stake accounts opened by the epoch refresh
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransientStakeKind {
+    Activating,
+    Deactivating,
+}
+
+/// Stake operations to perform after an epoch refresh
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EpochRefreshActions {
+    /// Merge the outstanding activating stake account into the reserve stake
+    pub merge_activating_stake: bool,
+    /// Withdraw the outstanding deactivated stake account into the vault
+    pub claim_deactivated_stake: bool,
+    /// Lamports to move into a new activating stake account
+    pub activate_amount: u64,
+    /// Lamports to split into a new deactivating stake account
+    pub deactivate_amount: u64,
+}
+
+/// Token mints owned by a Reserve
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReserveMint {
+    Liquidity,
+    Collateral,
+}
+
+impl ReserveMint {
+    /// Token name derived from the validator vote account and the Lending Market
+    pub fn token_name(&self, lending_market: &Pubkey, vote_account: &Pubkey) -> String {
+        let kind = match self {
+            ReserveMint::Liquidity => "Liquidity",
+            ReserveMint::Collateral => "Collateral",
+        };
+        format!("Icarus {} {} ({})", kind, &vote_account.to_string()[..8], &lending_market.to_string()[..4])
+    }
+
+    /// Token symbol derived from the validator vote account
+    pub fn token_symbol(&self, vote_account: &Pubkey) -> String {
+        let prefix = match self {
+            ReserveMint::Liquidity => "icL",
+            ReserveMint::Collateral => "icC",
+        };
+        format!("{}{}", prefix, &vote_account.to_string()[..4])
+    }
+}
+
 /// Initialize a reserve
 pub struct InitReserveParams {
     /// Last epoch when supply and rates updated
@@ -197,12 +441,18 @@ pub struct InitReserveParams {
     pub liquidity: ReserveLiquidity,
     /// Reserve Collateral
     pub collateral: ReserveCollateral,
+    /// Reserve Validator
+    pub validator: ReserveValidator,
     /// Bump seed for Reserve
     pub bump: u8,
     /// Bump seed for the Stake Account
     pub stake_bump: u8,
     /// Bump seed for the Vault Account
     pub vault_bump: u8,
+    /// Bump seed for the Liquidity Mint
+    pub liquidity_mint_bump: u8,
+    /// Bump seed for the Collateral Mint
+    pub collateral_mint_bump: u8,
 }
 
 /// Reserve liquidity
@@ -214,6 +464,7 @@ pub struct ReserveLiquidity {
     pub available_amount: u64,
     pub borrowed_amount: u64,
     pub cumulative_borrow_rate_wads: u128,
+    pub accumulated_protocol_fees: u64,
 }
 
 impl ReserveLiquidity {
@@ -225,6 +476,7 @@ impl ReserveLiquidity {
             available_amount: 0,
             borrowed_amount: 0,
             cumulative_borrow_rate_wads: WAD as u128,
+            accumulated_protocol_fees: 0,
         }
     }
 
@@ -295,6 +547,28 @@ impl ReserveLiquidity {
         Ok(())
     }
 
+    /// Repay the borrowed liquidity out of the claimed stake, then split the rest between
+    /// liquidity providers and protocol fees, the liquidity providers share raises the token exchange rate
+    pub fn claim(&mut self, claimed_amount: u64, borrowed_amount: u64, protocol_fee_percent: u8) -> Result<()> {
+        // Stake lost to slashing can leave part of the borrow unrecovered, and the
+        // outstanding borrows never go below zero
+        let repaid_amount = borrowed_amount.min(claimed_amount);
+        self.repay(repaid_amount, repaid_amount.min(self.borrowed_amount))?;
+
+        let earned_amount = claimed_amount - repaid_amount;
+        let protocol_fee = u64::try_from((earned_amount as u128)
+            .checked_mul(protocol_fee_percent as u128)
+            .and_then(|v| v.checked_div(100))
+            .ok_or(LendingError::MathOverflow)?
+        )?;
+
+        self.deposit(earned_amount.checked_sub(protocol_fee).ok_or(LendingError::MathOverflow)?)?;
+        self.accumulated_protocol_fees = self.accumulated_protocol_fees
+            .checked_add(protocol_fee)
+            .ok_or(LendingError::MathOverflow)?;
+        Ok(())
+    }
+
     pub fn liquidate(&mut self, liquidate_amount: u64) -> Result<()> {
         self.borrowed_amount = self.borrowed_amount
             .checked_sub(liquidate_amount)
@@ -323,7 +597,13 @@ pub struct ReserveCollateral {
     pub mint_total_supply: u64,
     pub stake_account: Pubkey,
     pub collateral_amount: u64,
-    pub collateral_amount_to_claim: u64
+    pub collateral_amount_to_claim: u64,
+    pub activating_stake_epoch: Option<u64>,
+    pub deactivating_stake_epoch: Option<u64>,
+    /// Borrowed liquidity of liquidated positions, repaid out of the collateral to claim
+    pub borrowed_amount_to_claim: u64,
+    /// Borrowed liquidity repaid out of the outstanding deactivating stake account
+    pub deactivating_borrowed_amount: u64,
 }
 
 impl ReserveCollateral {
@@ -334,6 +614,10 @@ impl ReserveCollateral {
             stake_account: params.stake_account,
             collateral_amount: 0,
             collateral_amount_to_claim: 0,
+            activating_stake_epoch: None,
+            deactivating_stake_epoch: None,
+            borrowed_amount_to_claim: 0,
+            deactivating_borrowed_amount: 0,
         }
     }
 
@@ -357,9 +641,14 @@ impl ReserveCollateral {
         )
     }
 
-    pub fn repay_or_liquidate(&mut self, amount: u64, interest_amount: u64, weighted_amount: u64,) -> Result<()> {
+    /// Remove settled collateral, queueing the claimed part for deactivation along with the
+    /// borrowed liquidity it repays on liquidation
+    pub fn repay_or_liquidate(&mut self, amount: u64, interest_amount: u64, borrowed_amount: u64, weighted_amount: u64) -> Result<()> {
         self.withdraw(amount)?;
         self.claim_interest(interest_amount)?;
+        self.borrowed_amount_to_claim = self.borrowed_amount_to_claim
+            .checked_add(borrowed_amount)
+            .ok_or(LendingError::MathOverflow)?;
         self.burn(weighted_amount)?;
 
         Ok(())
@@ -374,7 +663,7 @@ impl ReserveCollateral {
     }
 
     pub fn withdraw(&mut self, collateral_amount: u64) -> Result<()> {        
-        require_gte!(collateral_amount, self.collateral_amount, LendingError::InsufficientLiquidity);
+        require_gte!(self.collateral_amount, collateral_amount, LendingError::InsufficientLiquidity);
         Ok(self.collateral_amount = self.collateral_amount
             .checked_sub(collateral_amount)
             .ok_or(LendingError::MathOverflow)?
@@ -389,6 +678,210 @@ pub struct NewReserveCollateralParams {
     pub stake_account: Pubkey,
 }
 
+/// Reserve Validator
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct ReserveValidator {
+    pub commission: u8,
+    pub commission_epoch: u64,
+    pub previous_commission: u8,
+    pub last_epoch_credits: u64,
+    pub epochs_without_credits: u64,
+    pub reduced_risk: bool,
+    pub last_commission_rug_epoch: u64,
+    pub commission_rug_count: u32,
+    /// Commission recorded for each of the last epochs, indexed by epoch modulo its length
+    pub commission_history: [u8; COMMISSION_HISTORY_EPOCHS],
+}
+
+impl Default for ReserveValidator {
+    fn default() -> Self {
+        Self::new(NewReserveValidatorParams { commission: 0, current_epoch: 0 })
+    }
+}
+
+impl ReserveValidator {
+    pub fn new(params: NewReserveValidatorParams) -> Self {
+        Self {
+            commission: params.commission,
+            commission_epoch: params.current_epoch,
+            previous_commission: params.commission,
+            last_epoch_credits: 0,
+            epochs_without_credits: 0,
+            reduced_risk: false,
+            last_commission_rug_epoch: 0,
+            commission_rug_count: 0,
+            commission_history: [params.commission; COMMISSION_HISTORY_EPOCHS],
+        }
+    }
+
+    /// Record the validator performance and switch reduced-risk mode on or off
+    pub fn update(&mut self, vote_state: &VoteState, current_epoch: Epoch, config: &LendingMarketConfig) {
+        self.update_commission(vote_state.commission, current_epoch, config);
+        self.last_epoch_credits = get_epoch_credits(vote_state, current_epoch.saturating_sub(1));
+        self.epochs_without_credits = get_epochs_without_credits(vote_state, current_epoch);
+
+        let reduced_risk = self.commission > config.max_commission
+            || self.epochs_without_credits >= config.max_epochs_without_credits;
+
+        if reduced_risk != self.reduced_risk {
+            msg!("Reserve reduced-risk mode: {}", reduced_risk);
+        }
+        self.reduced_risk = reduced_risk;
+    }
+
+    /// Record the commission for the epoch and flag the validator if it spiked since the last refresh.
+    /// Epochs skipped since the last refresh keep the commission recorded back then
+    pub fn update_commission(&mut self, commission: u8, current_epoch: Epoch, config: &LendingMarketConfig) {
+        let first_skipped_epoch = self.commission_epoch
+            .saturating_add(1)
+            .max(current_epoch.saturating_sub(COMMISSION_HISTORY_EPOCHS as u64 - 1));
+        for epoch in first_skipped_epoch..current_epoch {
+            self.commission_history[Self::history_index(epoch)] = self.commission;
+        }
+        self.commission_history[Self::history_index(current_epoch)] = commission;
+
+        self.previous_commission = self.commission;
+        self.commission = commission;
+        self.commission_epoch = current_epoch;
+
+        if self.commission.saturating_sub(self.previous_commission) > config.max_commission_increase {
+            msg!("Commission rug detected: {}% -> {}%", self.previous_commission, self.commission);
+            self.last_commission_rug_epoch = current_epoch;
+            self.commission_rug_count = self.commission_rug_count.saturating_add(1);
+        }
+    }
+
+    /// Check if the commission recorded in any epoch after the given one rose more than
+    /// `max_commission_increase` above the commission of that epoch. Loans older than the
+    /// history are compared against its oldest epoch
+    pub fn is_commission_rugged_since(&self, epoch: Epoch, max_commission_increase: u8) -> bool {
+        let first_epoch = epoch.max(self.commission_epoch.saturating_sub(COMMISSION_HISTORY_EPOCHS as u64 - 1));
+        if first_epoch >= self.commission_epoch {
+            return false;
+        }
+
+        let start_commission = self.commission_history[Self::history_index(first_epoch)];
+        (first_epoch + 1..=self.commission_epoch)
+            .any(|epoch| self.commission_history[Self::history_index(epoch)].saturating_sub(start_commission) > max_commission_increase)
+    }
+
+    fn history_index(epoch: Epoch) -> usize {
+        (epoch % COMMISSION_HISTORY_EPOCHS as u64) as usize
+    }
+}
+
+/// Reserve Stake Rewards
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, InitSpace)]
+pub struct ReserveRewards {
+    /// Lamports of the reserve stake account at the last snapshot
+    pub stake_lamports: u64,
+    /// Delegated stake of the reserve stake account at the last snapshot
+    pub delegated_stake: u64,
+    /// Inflation rewards measured at the last epoch refresh
+    pub inflation_rewards: u64,
+    /// MEV tips and donations measured at the last epoch refresh
+    pub extra_rewards: u64,
+    /// Rewards earned per epoch over the stake at the last snapshot, as a Wad
+    pub reward_rate_wads: u64,
+    /// Total rewards measured since the reserve creation
+    pub total_rewards: u64,
+}
+
+impl ReserveRewards {
+    /// Measure the rewards earned since the last snapshot. Inflation rewards are
+    /// compounded in the delegation, anything else landing on the account is a tip or a donation.
+    /// The rate is measured over the delegated stake, which excludes the rent and inactive lamports
+    pub fn measure(&mut self, stake_lamports: u64, delegated_stake: u64, epochs_elapsed: u64) -> Result<()> {
+        let rewards = stake_lamports.saturating_sub(self.stake_lamports);
+        self.inflation_rewards = delegated_stake.saturating_sub(self.delegated_stake).min(rewards);
+        self.extra_rewards = rewards
+            .checked_sub(self.inflation_rewards)
+            .ok_or(LendingError::MathOverflow)?;
+        self.total_rewards = self.total_rewards
+            .checked_add(rewards)
+            .ok_or(LendingError::MathOverflow)?;
+
+        self.reward_rate_wads = if self.delegated_stake == 0 || epochs_elapsed == 0 {
+            0
+        } else {
+            u64::try_from((rewards as u128)
+                .checked_mul(WAD as u128)
+                .and_then(|v| v.checked_div(self.delegated_stake as u128))
+                .and_then(|v| v.checked_div(epochs_elapsed as u128))
+                .ok_or(LendingError::MathOverflow)?
+            )?
+        };
+
+        Ok(())
+    }
+
+    /// Record the state of the reserve stake account after the program moved stake in or out of it
+    pub fn snapshot(&mut self, stake_lamports: u64, delegated_stake: u64) {
+        self.stake_lamports = stake_lamports;
+        self.delegated_stake = delegated_stake;
+    }
+
+    /// Record stake returned to the reserve stake account outside of an epoch refresh, so it
+    /// isn't measured as rewards
+    pub fn record_returned_stake(&mut self, amount: u64) -> Result<()> {
+        self.stake_lamports = self.stake_lamports
+            .checked_add(amount)
+            .ok_or(LendingError::MathOverflow)?;
+
+        Ok(())
+    }
+}
+
+/// Minimum fees charged when positions are settled, in bps of the borrowed amount.
+/// They go to the liquidity providers with the rest of the settlement fee
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct ReserveFees {
+    /// Minimum fee on every repayment
+    pub minimum_fee_bps: u16,
+    /// Minimum fee when the loan is settled in the epoch it started, before any reward accrues
+    pub same_epoch_fee_bps: u16,
+}
+
+impl Default for ReserveFees {
+    fn default() -> Self {
+        Self {
+            minimum_fee_bps: MINIMUM_REPAY_FEE as u16,
+            same_epoch_fee_bps: MINIMUM_FEE as u16,
+        }
+    }
+}
+
+impl ReserveFees {
+    /// Validate the fees, when initializing or modifying the reserve
+    pub fn validate(&self) -> Result<()> {
+        if self.minimum_fee_bps as u64 > MAX_BASIS_POINTS {
+            msg!("Minimum fee must be in range [0, 10000] bps");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        if self.same_epoch_fee_bps < self.minimum_fee_bps || self.same_epoch_fee_bps as u64 > MAX_BASIS_POINTS {
+            msg!("Same epoch fee must be in range [minimum fee, 10000] bps");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        Ok(())
+    }
+
+    /// Minimum fee, in bps, of a loan started at the given epoch
+    pub fn minimum_fee_bps(&self, loan_start_epoch: Epoch, current_epoch: Epoch) -> u64 {
+        if current_epoch <= loan_start_epoch {
+            self.same_epoch_fee_bps as u64
+        } else {
+            self.minimum_fee_bps as u64
+        }
+    }
+}
+
+/// New reserve validator parameters
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct NewReserveValidatorParams {
+    pub commission: u8,
+    pub current_epoch: Epoch,
+}
+
 // // ToDo: Run Calculation for ReserveConfig
 // #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
 // pub struct ReserveConfig {
@@ -553,3 +1046,449 @@ pub struct NewReserveCollateralParams {
 // }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserve_at_epoch(epoch: Epoch) -> Reserve {
+        Reserve::new(InitReserveParams {
+            current_epoch: epoch,
+            current_slot: 0,
+            lending_market: Pubkey::new_unique(),
+            vote_account: Pubkey::new_unique(),
+            liquidity: ReserveLiquidity::new(NewReserveLiquidityParams {
+                mint_pubkey: Pubkey::new_unique(),
+                vault_pubkey: Pubkey::new_unique(),
+            }),
+            collateral: ReserveCollateral::new(NewReserveCollateralParams {
+                mint_pubkey: Pubkey::new_unique(),
+                stake_account: Pubkey::new_unique(),
+            }),
+            validator: ReserveValidator::new(NewReserveValidatorParams {
+                commission: 5,
+                current_epoch: epoch,
+            }),
+            bump: 255,
+            stake_bump: 255,
+            vault_bump: 255,
+            liquidity_mint_bump: 255,
+            collateral_mint_bump: 255,
+        })
+    }
+
+    fn refresh_params<'a>(current_epoch: Epoch, vote_state: &'a VoteState, config: &'a LendingMarketConfig) -> EpochRefreshParams<'a> {
+        EpochRefreshParams {
+            current_epoch,
+            vote_state,
+            config,
+            stake_lamports: 5 * LAMPORTS_PER_SOL,
+            delegated_stake: 3 * LAMPORTS_PER_SOL,
+            inactive_stake_amount: 2 * LAMPORTS_PER_SOL,
+            minimum_stake_amount: LAMPORTS_PER_SOL,
+            is_activating_stake_active: true,
+            is_deactivating_stake_inactive: true,
+            deactivated_stake_amount: LAMPORTS_PER_SOL,
+        }
+    }
+
+    #[test]
+    fn test_refresh_epoch_is_persisted_and_idempotent() {
+        let vote_state = VoteState::default();
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        reserve.collateral.claim_interest(LAMPORTS_PER_SOL).unwrap();
+
+        let actions = reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().unwrap();
+        assert_eq!(actions.activate_amount, 2 * LAMPORTS_PER_SOL);
+        assert_eq!(actions.deactivate_amount, LAMPORTS_PER_SOL);
+        assert_eq!(reserve.last_epoch, 11);
+        assert_eq!(reserve.collateral.collateral_amount_to_claim, 0);
+        assert_eq!(reserve.collateral.activating_stake_epoch, Some(11));
+        assert_eq!(reserve.collateral.deactivating_stake_epoch, Some(11));
+
+        // A second refresh in the same epoch must leave the reserve untouched
+        let state = reserve.try_to_vec().unwrap();
+        assert!(reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().is_none());
+        assert_eq!(reserve.try_to_vec().unwrap(), state);
+    }
+
+    #[test]
+    fn test_refresh_epoch_catches_up_skipped_epochs() {
+        let vote_state = VoteState::default();
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+
+        reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().unwrap();
+
+        // Transient accounts still warming up stay outstanding across the jump
+        let mut params = refresh_params(15, &vote_state, &config);
+        params.is_activating_stake_active = false;
+        let actions = reserve.refresh_epoch(params).unwrap().unwrap();
+        assert!(!actions.merge_activating_stake);
+        assert_eq!(actions.activate_amount, 0);
+        assert_eq!(reserve.collateral.activating_stake_epoch, Some(11));
+        assert_eq!(reserve.last_epoch, 15);
+
+        let actions = reserve.refresh_epoch(refresh_params(16, &vote_state, &config)).unwrap().unwrap();
+        assert!(actions.merge_activating_stake);
+        assert_eq!(reserve.collateral.activating_stake_epoch, Some(16));
+    }
+
+    #[test]
+    fn test_claimed_stake_is_credited_to_liquidity_providers() {
+        let vote_state = VoteState::default();
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        reserve.deposit(10 * LAMPORTS_PER_SOL).unwrap();
+        reserve.collateral.claim_interest(LAMPORTS_PER_SOL).unwrap();
+
+        reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().unwrap();
+        assert_eq!(reserve.liquidity.available_amount, 10 * LAMPORTS_PER_SOL);
+
+        let actions = reserve.refresh_epoch(refresh_params(12, &vote_state, &config)).unwrap().unwrap();
+        assert!(actions.claim_deactivated_stake);
+
+        let protocol_fee = LAMPORTS_PER_SOL * config.protocol_fee_percent as u64 / 100;
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees, protocol_fee);
+        assert_eq!(reserve.liquidity.available_amount, 11 * LAMPORTS_PER_SOL - protocol_fee);
+
+        // Liquidity providers redeem their share of the claimed stake
+        assert_eq!(reserve.reedem(10 * LAMPORTS_PER_SOL).unwrap(), 11 * LAMPORTS_PER_SOL - protocol_fee);
+    }
+
+    #[test]
+    fn test_reserve_fees_validation() {
+        assert!(ReserveFees::default().validate().is_ok());
+        assert!(ReserveFees { minimum_fee_bps: 0, same_epoch_fee_bps: 0 }.validate().is_ok());
+        assert!(ReserveFees { minimum_fee_bps: 100, same_epoch_fee_bps: 10 }.validate().is_err());
+        assert!(ReserveFees { minimum_fee_bps: 10, same_epoch_fee_bps: 10_001 }.validate().is_err());
+        assert!(ReserveFees { minimum_fee_bps: 10_001, same_epoch_fee_bps: 10_001 }.validate().is_err());
+    }
+
+    fn vote_state_with_credits(credited_epochs: &[Epoch]) -> VoteState {
+        let mut vote_state = VoteState::default();
+        for epoch in credited_epochs {
+            vote_state.increment_credits(*epoch, 1);
+        }
+        vote_state
+    }
+
+    #[test]
+    fn test_epochs_without_credits() {
+        assert_eq!(get_epochs_without_credits(&vote_state_with_credits(&[]), 10), 10);
+        assert_eq!(get_epochs_without_credits(&vote_state_with_credits(&[9]), 10), 0);
+        assert_eq!(get_epochs_without_credits(&vote_state_with_credits(&[7, 8]), 10), 1);
+        assert_eq!(get_epochs_without_credits(&vote_state_with_credits(&[5]), 10), 4);
+        // Credits of the current epoch are not final yet
+        assert_eq!(get_epochs_without_credits(&vote_state_with_credits(&[7, 10]), 10), 2);
+    }
+
+    #[test]
+    fn test_validator_update_switches_reduced_risk() {
+        let config = LendingMarketConfig::default();
+        let mut validator = ReserveValidator::new(NewReserveValidatorParams { commission: 5, current_epoch: 10 });
+
+        validator.update(&vote_state_with_credits(&[9, 10]), 10, &config);
+        assert_eq!(validator.last_epoch_credits, 1);
+        assert_eq!(validator.epochs_without_credits, 0);
+        assert!(!validator.reduced_risk);
+
+        // Missing credits for too long
+        validator.update(&vote_state_with_credits(&[9]), 10 + config.max_epochs_without_credits, &config);
+        assert_eq!(validator.last_epoch_credits, 0);
+        assert_eq!(validator.epochs_without_credits, config.max_epochs_without_credits);
+        assert!(validator.reduced_risk);
+
+        // Back to voting
+        validator.update(&vote_state_with_credits(&[9, 13]), 14, &config);
+        assert!(!validator.reduced_risk);
+
+        // Commission above the market maximum
+        let mut vote_state = vote_state_with_credits(&[14]);
+        vote_state.commission = config.max_commission + 1;
+        validator.update(&vote_state, 15, &config);
+        assert!(validator.reduced_risk);
+
+        vote_state.commission = config.max_commission;
+        validator.update(&vote_state, 15, &config);
+        assert!(!validator.reduced_risk);
+    }
+
+    #[test]
+    fn test_reduced_risk_pauses_borrows_and_lowers_max_ratio() {
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        let loan_type = LoanType::MEDIUM(10);
+
+        assert!(reserve.check_borrows_allowed().is_ok());
+        assert_eq!(reserve.max_ratio(&loan_type, &config).unwrap(), loan_type.max_ratio());
+
+        reserve.validator.reduced_risk = true;
+        assert!(reserve.check_borrows_allowed().is_err());
+        assert_eq!(
+            reserve.max_ratio(&loan_type, &config).unwrap(),
+            loan_type.max_ratio() * config.reduced_risk_ltv_percent as u64 / 100
+        );
+    }
+
+    /// Validator created at epoch 10 with a 5% commission, then refreshed with the given commission per epoch
+    fn validator_with_commissions(commissions: &[(Epoch, u8)]) -> ReserveValidator {
+        let config = LendingMarketConfig::default();
+        let mut validator = ReserveValidator::new(NewReserveValidatorParams { commission: 5, current_epoch: 10 });
+        for (epoch, commission) in commissions {
+            validator.update_commission(*commission, *epoch, &config);
+        }
+        validator
+    }
+
+    #[test]
+    fn test_commission_rug_followed_by_reset_is_detected() {
+        let validator = validator_with_commissions(&[(11, 100), (12, 5), (13, 5)]);
+        assert_eq!(validator.commission_rug_count, 1);
+        assert_eq!(validator.last_commission_rug_epoch, 11);
+        assert!(validator.is_commission_rugged_since(10, 10));
+        // The rug happened before the loan started
+        assert!(!validator.is_commission_rugged_since(11, 10));
+        assert!(!validator.is_commission_rugged_since(12, 10));
+    }
+
+    #[test]
+    fn test_gradual_commission_increase_is_detected_from_loan_start() {
+        let validator = validator_with_commissions(&[(11, 12), (12, 19), (13, 26)]);
+        // No increase between two refreshes is large enough to flag the validator
+        assert_eq!(validator.commission_rug_count, 0);
+        assert!(validator.is_commission_rugged_since(10, 10));
+        assert!(validator.is_commission_rugged_since(11, 10));
+        assert!(!validator.is_commission_rugged_since(12, 10));
+    }
+
+    #[test]
+    fn test_loan_started_on_a_rugged_validator_is_not_waived() {
+        let validator = validator_with_commissions(&[(11, 100), (12, 100), (13, 100)]);
+        assert!(validator.is_commission_rugged_since(10, 10));
+        assert!(!validator.is_commission_rugged_since(11, 10));
+        // Loans started after the last refresh have no recorded epoch to compare
+        assert!(!validator.is_commission_rugged_since(13, 10));
+        assert!(!validator.is_commission_rugged_since(20, 10));
+    }
+
+    #[test]
+    fn test_skipped_epochs_keep_the_last_recorded_commission() {
+        let validator = validator_with_commissions(&[(15, 100)]);
+        for epoch in 10..15 {
+            assert_eq!(validator.commission_history[epoch as usize], 5);
+        }
+        assert_eq!(validator.commission_history[15], 100);
+        assert!(validator.is_commission_rugged_since(12, 10));
+        assert!(!validator.is_commission_rugged_since(15, 10));
+    }
+
+    #[test]
+    fn test_commission_history_wraps_around() {
+        let last_epoch = 10 + COMMISSION_HISTORY_EPOCHS as u64 + 5;
+        let validator = validator_with_commissions(&[(11, 100), (12, 5), (last_epoch, 5)]);
+        // The rug fell out of the history
+        assert!(!validator.is_commission_rugged_since(10, 10));
+
+        let validator = validator_with_commissions(&[(last_epoch - 1, 5), (last_epoch, 50)]);
+        // Loans older than the history compare against its oldest epoch
+        assert!(validator.is_commission_rugged_since(0, 10));
+        assert!(validator.is_commission_rugged_since(last_epoch - 1, 10));
+    }
+
+    #[test]
+    fn test_fee_waiver_follows_the_market_rule() {
+        let mut config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        reserve.validator.update_commission(100, 11, &config);
+
+        assert!(reserve.is_fee_waived(&config, 10));
+        assert!(!reserve.is_fee_waived(&config, 11));
+
+        config.commission_rug_protection = false;
+        assert!(!reserve.is_fee_waived(&config, 10));
+    }
+
+    #[test]
+    fn test_reward_rate_is_measured_over_delegated_stake() {
+        let rent = 2_282_880;
+        let mut rewards = ReserveRewards::default();
+        rewards.snapshot(100 * LAMPORTS_PER_SOL + rent + LAMPORTS_PER_SOL, 100 * LAMPORTS_PER_SOL);
+
+        // 1 SOL of inflation and 0.5 SOL of tips over 2 epochs
+        rewards.measure(102 * LAMPORTS_PER_SOL + LAMPORTS_PER_SOL / 2 + rent, 101 * LAMPORTS_PER_SOL, 2).unwrap();
+        assert_eq!(rewards.inflation_rewards, LAMPORTS_PER_SOL);
+        assert_eq!(rewards.extra_rewards, LAMPORTS_PER_SOL / 2);
+        assert_eq!(rewards.total_rewards, LAMPORTS_PER_SOL + LAMPORTS_PER_SOL / 2);
+        // 1.5% over 2 epochs, ignoring the rent and the inactive lamports
+        assert_eq!(rewards.reward_rate_wads, WAD / 10_000 * 75);
+    }
+
+    #[test]
+    fn test_reward_rate_without_delegated_stake_or_elapsed_epochs() {
+        let mut rewards = ReserveRewards::default();
+        rewards.snapshot(LAMPORTS_PER_SOL, 0);
+        rewards.measure(2 * LAMPORTS_PER_SOL, 0, 1).unwrap();
+        assert_eq!(rewards.reward_rate_wads, 0);
+        assert_eq!(rewards.extra_rewards, LAMPORTS_PER_SOL);
+
+        rewards.snapshot(LAMPORTS_PER_SOL, LAMPORTS_PER_SOL);
+        rewards.measure(2 * LAMPORTS_PER_SOL, LAMPORTS_PER_SOL, 0).unwrap();
+        assert_eq!(rewards.reward_rate_wads, 0);
+    }
+
+    #[test]
+    fn test_accrued_rewards_go_to_the_collateral() {
+        let mut reserve = reserve_at_epoch(10);
+        reserve.collateral.deposit(50 * LAMPORTS_PER_SOL).unwrap();
+        reserve.rewards.snapshot(100 * LAMPORTS_PER_SOL + LAMPORTS_PER_SOL, 100 * LAMPORTS_PER_SOL);
+
+        // 1% per epoch over 2 epochs
+        reserve.accrue_rewards(103 * LAMPORTS_PER_SOL, 102 * LAMPORTS_PER_SOL, 2).unwrap();
+        assert_eq!(reserve.rewards.reward_rate_wads, WAD / 100);
+        assert_eq!(reserve.collateral.collateral_amount, 51 * LAMPORTS_PER_SOL);
+    }
+
+    #[test]
+    fn test_migrated_reserve_keeps_keypair_mints() {
+        let (liquidity_mint, collateral_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let legacy = ReserveV1 {
+            version: 1,
+            lending_market: Pubkey::new_unique(),
+            vote_account: Pubkey::new_unique(),
+            last_epoch: 10,
+            last_update: LastUpdate::new(0),
+            liquidity: ReserveLiquidityV1 {
+                mint_pubkey: liquidity_mint,
+                mint_total_supply: LAMPORTS_PER_SOL,
+                vault_pubkey: Pubkey::new_unique(),
+                available_amount: LAMPORTS_PER_SOL,
+                borrowed_amount: 0,
+                cumulative_borrow_rate_wads: 0,
+            },
+            collateral: ReserveCollateralV1 {
+                mint_pubkey: collateral_mint,
+                mint_total_supply: 0,
+                stake_account: Pubkey::new_unique(),
+                collateral_amount: 0,
+                collateral_amount_to_claim: 0,
+            },
+            bump: 255,
+            stake_bump: 255,
+            vault_bump: 255,
+        };
+
+        let reserve_key = Pubkey::new_unique();
+        let mut reserve = Reserve::migrate(1, &legacy.try_to_vec().unwrap()).unwrap();
+        reserve.set_mint_bumps(&reserve_key);
+        assert!(reserve.liquidity.mint_pubkey == liquidity_mint);
+        assert!(reserve.collateral.mint_pubkey == collateral_mint);
+        assert_eq!(reserve.liquidity_mint_bump, 0);
+        assert_eq!(reserve.collateral_mint_bump, 0);
+
+        // Mints derived from the reserve keep their bump
+        let (liquidity_mint, liquidity_mint_bump) = Pubkey::find_program_address(&[LIQUIDITY_MINT_PREFIX, reserve_key.as_ref()], &crate::ID);
+        reserve.liquidity.mint_pubkey = liquidity_mint;
+        reserve.set_mint_bumps(&reserve_key);
+        assert_eq!(reserve.liquidity_mint_bump, liquidity_mint_bump);
+        assert_eq!(reserve.collateral_mint_bump, 0);
+    }
+
+    #[test]
+    fn test_returned_transient_stake_is_not_measured_as_rewards() {
+        let mut reserve = reserve_at_epoch(10);
+        reserve.collateral.deposit(50 * LAMPORTS_PER_SOL).unwrap();
+        reserve.rewards.snapshot(100 * LAMPORTS_PER_SOL, 100 * LAMPORTS_PER_SOL);
+
+        // Stake left in a spent transient account goes back to the reserve stake
+        reserve.rewards.record_returned_stake(LAMPORTS_PER_SOL).unwrap();
+        reserve.accrue_rewards(101 * LAMPORTS_PER_SOL, 100 * LAMPORTS_PER_SOL, 1).unwrap();
+        assert_eq!(reserve.rewards.total_rewards, 0);
+        assert_eq!(reserve.collateral.collateral_amount, 50 * LAMPORTS_PER_SOL);
+    }
+
+    #[test]
+    fn test_liquidated_stake_repays_the_borrow_before_crediting_income() {
+        let vote_state = VoteState::default();
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        reserve.deposit(10 * LAMPORTS_PER_SOL).unwrap();
+        reserve.liquidity.borrow(LAMPORTS_PER_SOL / 2).unwrap();
+        reserve.collateral.deposit(LAMPORTS_PER_SOL).unwrap();
+        reserve.collateral.mint(LAMPORTS_PER_SOL).unwrap();
+
+        // A position borrowing 0.5 SOL against 1 SOL of stake is liquidated
+        reserve.collateral.repay_or_liquidate(LAMPORTS_PER_SOL, LAMPORTS_PER_SOL, LAMPORTS_PER_SOL / 2, LAMPORTS_PER_SOL).unwrap();
+        assert_eq!(reserve.collateral.borrowed_amount_to_claim, LAMPORTS_PER_SOL / 2);
+
+        reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().unwrap();
+        assert_eq!(reserve.collateral.borrowed_amount_to_claim, 0);
+        assert_eq!(reserve.collateral.deactivating_borrowed_amount, LAMPORTS_PER_SOL / 2);
+
+        reserve.refresh_epoch(refresh_params(12, &vote_state, &config)).unwrap().unwrap();
+        assert_eq!(reserve.collateral.deactivating_borrowed_amount, 0);
+        assert_eq!(reserve.liquidity.borrowed_amount, 0);
+
+        // Only the stake above the borrow is income
+        let protocol_fee = LAMPORTS_PER_SOL / 2 * config.protocol_fee_percent as u64 / 100;
+        assert_eq!(reserve.liquidity.accumulated_protocol_fees, protocol_fee);
+        assert_eq!(reserve.liquidity.available_amount, 10 * LAMPORTS_PER_SOL + LAMPORTS_PER_SOL / 2 - protocol_fee);
+    }
+
+    #[test]
+    fn test_claim_short_of_the_borrow_credits_no_income() {
+        let mut liquidity = ReserveLiquidity::new(NewReserveLiquidityParams {
+            mint_pubkey: Pubkey::new_unique(),
+            vault_pubkey: Pubkey::new_unique(),
+        });
+        liquidity.deposit(10 * LAMPORTS_PER_SOL).unwrap();
+        liquidity.borrow(2 * LAMPORTS_PER_SOL).unwrap();
+
+        liquidity.claim(LAMPORTS_PER_SOL, 2 * LAMPORTS_PER_SOL, 10).unwrap();
+        assert_eq!(liquidity.borrowed_amount, LAMPORTS_PER_SOL);
+        assert_eq!(liquidity.available_amount, 9 * LAMPORTS_PER_SOL);
+        assert_eq!(liquidity.accumulated_protocol_fees, 0);
+    }
+
+    #[test]
+    fn test_only_spent_transient_stake_is_closable() {
+        let vote_state = VoteState::default();
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        reserve.collateral.claim_interest(LAMPORTS_PER_SOL).unwrap();
+
+        // Epoch 11 opens both transient accounts, epoch 12 settles them and opens a new activating one
+        reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().unwrap();
+        reserve.refresh_epoch(refresh_params(12, &vote_state, &config)).unwrap().unwrap();
+        assert_eq!(reserve.collateral.activating_stake_epoch, Some(12));
+        assert_eq!(reserve.collateral.deactivating_stake_epoch, None);
+
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Activating, 11).is_ok());
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Deactivating, 11).is_ok());
+        // Outstanding, current or future epochs
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Activating, 12).is_err());
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Deactivating, 12).is_err());
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Deactivating, 13).is_err());
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Activating, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_outstanding_transient_stake_from_a_past_epoch_is_not_closable() {
+        let vote_state = VoteState::default();
+        let config = LendingMarketConfig::default();
+        let mut reserve = reserve_at_epoch(10);
+        reserve.collateral.claim_interest(LAMPORTS_PER_SOL).unwrap();
+        reserve.refresh_epoch(refresh_params(11, &vote_state, &config)).unwrap().unwrap();
+
+        // The accounts of epoch 11 are still warming up and cooling down at epoch 12
+        let mut params = refresh_params(12, &vote_state, &config);
+        params.is_activating_stake_active = false;
+        params.is_deactivating_stake_inactive = false;
+        reserve.refresh_epoch(params).unwrap().unwrap();
+
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Activating, 11).is_err());
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Deactivating, 11).is_err());
+        assert!(reserve.check_transient_stake_closable(TransientStakeKind::Deactivating, 10).is_ok());
+    }
+}